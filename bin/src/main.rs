@@ -2,8 +2,13 @@ extern crate clap;
 extern crate lapimposteur_lib as lapimposteur;
 extern crate log;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand};
+use lapimposteur::model::imposter::{
+    ActionSpec, FailurePolicy, HeaderTemplate, HeaderValueSpec, HeadersSpec, PayloadTemplate,
+    RouteSpec, ScheduleSpec, VarSpec, VariablesSpec,
+};
 use lapimposteur::server;
+use std::process;
 
 fn main() {
     env_logger::init();
@@ -19,11 +24,162 @@ fn main() {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .subcommand(
+            SubCommand::with_name("publish")
+                .about("Publishes messages built like the messages of an action")
+                .arg(
+                    Arg::with_name("connection")
+                        .short("u")
+                        .long("connection")
+                        .value_name("URL")
+                        .help("The amqp url of the broker")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("exchange")
+                        .short("x")
+                        .long("exchange")
+                        .value_name("NAME")
+                        .help("The exchange to publish to")
+                        .takes_value(true)
+                        .default_value(""),
+                )
+                .arg(
+                    Arg::with_name("routing-key")
+                        .short("r")
+                        .long("routing-key")
+                        .value_name("KEY")
+                        .help("The routing key")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("header")
+                        .short("H")
+                        .long("header")
+                        .value_name("NAME=TEMPLATE")
+                        .help("Sets a header, which can use the variables")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("var")
+                        .short("v")
+                        .long("var")
+                        .value_name("NAME=SPEC")
+                        .help("Sets a variable, given as a string or as its json specification")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("payload")
+                        .short("p")
+                        .long("payload")
+                        .value_name("TEMPLATE")
+                        .help("The payload template")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("payload-file")
+                        .short("f")
+                        .long("payload-file")
+                        .value_name("FILE")
+                        .help("The file of the payload template")
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::with_name("body")
+                        .args(&["payload", "payload-file"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("count")
+                        .short("n")
+                        .long("count")
+                        .value_name("N")
+                        .help("The number of messages to publish")
+                        .takes_value(true)
+                        .default_value("1"),
+                ),
+        )
         .get_matches();
 
+    if let Some(publish) = matches.subcommand_matches("publish") {
+        if let Err(e) = run_publish(publish) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     let config = matches
         .value_of("config")
         .expect("No config file given. Use --help.");
 
     server::run(config);
 }
+
+fn run_publish(matches: &ArgMatches) -> Result<(), String> {
+    let connection = matches.value_of("connection").unwrap_or("");
+    let count = matches
+        .value_of("count")
+        .unwrap_or("1")
+        .parse::<u32>()
+        .map_err(|e| format!("Invalid count: {}", e))?;
+    let mut headers = HeadersSpec::new();
+    for (name, value) in named_values(matches, "header")? {
+        headers.insert(name, HeaderValueSpec::Template(HeaderTemplate::Str(value)));
+    }
+    let mut variables = VariablesSpec::new();
+    for (name, spec) in named_values(matches, "var")? {
+        if name == "iteration" {
+            return Err(
+                "The iteration variable is reserved for the index of the message".to_owned(),
+            );
+        }
+        let spec = VarSpec::parse(&spec).map_err(|e| e.to_string())?;
+        variables.insert(name, spec);
+    }
+    let payload = match matches.value_of("payload-file") {
+        Some(file) => PayloadTemplate::File(file.to_owned()),
+        None => PayloadTemplate::Inline(matches.value_of("payload").unwrap_or("").to_owned()),
+    };
+    let action = ActionSpec {
+        to: RouteSpec {
+            exchange: matches.value_of("exchange").map(str::to_owned),
+            routing_key: matches.value_of("routing-key").map(str::to_owned),
+            reply: false,
+        },
+        variables,
+        payload,
+        headers,
+        schedule: ScheduleSpec::from_secs(0),
+        on_error: FailurePolicy::Fail,
+        repeat: None,
+        faults: None,
+        toxic: None,
+    };
+    server::publish_messages(connection, action, count).map_err(|e| e.to_string())
+}
+
+/// Reads the `NAME=VALUE` values of an argument.
+fn named_values(matches: &ArgMatches, arg: &str) -> Result<Vec<(String, String)>, String> {
+    matches
+        .values_of(arg)
+        .map(|values| values.collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|value| {
+            let mut parts = value.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if !name.is_empty() => {
+                    Ok((name.to_owned(), value.to_owned()))
+                }
+                _ => Err(format!("Expected NAME=VALUE for --{}, not {}", arg, value)),
+            }
+        })
+        .collect()
+}
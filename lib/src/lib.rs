@@ -1,8 +1,10 @@
+extern crate base64;
 extern crate chrono;
 extern crate cron;
 #[macro_use]
 extern crate failure;
 extern crate futures;
+extern crate handlebars;
 extern crate jsonpath;
 extern crate lapin_futures_rustls;
 #[macro_use]
@@ -16,6 +18,7 @@ extern crate mustache;
 extern crate rand;
 #[macro_use]
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 extern crate uuid;
 
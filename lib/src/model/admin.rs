@@ -0,0 +1,64 @@
+use super::imposter::{ExchangeName, QueueName, RoutingKey};
+use super::toxic::ToxicCommand;
+use failure::Error;
+
+/// The queue on which Lapimposteur receives its admin commands.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct AdminSpec {
+  pub queue: QueueName,
+  pub exchange: ExchangeName,
+  pub routing_key: RoutingKey,
+}
+
+/// A command received on the admin queue, as json.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum AdminCommand {
+  /// Applies a toxic to the reactor consuming the queue.
+  Toxic {
+    queue: QueueName,
+    #[serde(flatten)]
+    toxic: ToxicCommand,
+  },
+}
+
+impl AdminCommand {
+  pub fn parse(payload: &[u8]) -> Result<AdminCommand, Error> {
+    let command: AdminCommand =
+      serde_json::from_slice(payload).map_err(|e| format_err!("Invalid admin command: {}", e))?;
+    match command {
+      AdminCommand::Toxic { ref toxic, .. } => toxic.validate()?,
+    }
+    Ok(command)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::super::toxic::ToxicEffect;
+  use super::*;
+
+  #[test]
+  fn should_parse_a_toxic_command() {
+    let command = AdminCommand::parse(
+      br#"{ "Toxic": { "queue": "orders-q", "effect": "CancelConsumer", "resume_after": "30s" } }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+      AdminCommand::Toxic {
+        queue: "orders-q".to_owned(),
+        toxic: ToxicCommand {
+          effect: ToxicEffect::CancelConsumer,
+          resume_after: Some("30s".to_owned()),
+        },
+      },
+      command
+    );
+    assert!(AdminCommand::parse(
+      br#"{ "Toxic": { "queue": "orders-q", "effect": "CloseChannel", "resume_after": "soon" } }"#
+    )
+    .is_err());
+    assert!(AdminCommand::parse(b"close").is_err());
+  }
+}
@@ -0,0 +1,299 @@
+use super::imposter::{Lit, Variables};
+use super::time::{self, parse_duration, TimeUnit};
+use chrono::Utc;
+use failure::Error;
+use rand::distributions::{Distribution, Exp, Normal};
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// A delay, either fixed or drawn from a distribution. Durations are given in a human format, e.g. `1m30s` or `250ms`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum DelaySpec {
+  Fixed(String),
+  Uniform {
+    min: String,
+    max: String,
+  },
+  Normal {
+    mean: String,
+    std_dev: String,
+  },
+  Exponential {
+    mean: String,
+  },
+  /// Percentile (e.g. `"99.9"`) -> duration.
+  Percentiles(BTreeMap<String, String>),
+}
+
+/// A delay, or a date, read from a variable. Numbers are expressed in `unit`, milliseconds by default.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct VarDelay {
+  pub var: String,
+  #[serde(default = "default_unit")]
+  pub unit: TimeUnit,
+}
+
+fn default_unit() -> TimeUnit {
+  TimeUnit::Millis
+}
+
+fn unit_millis(unit: &TimeUnit) -> f64 {
+  match unit {
+    TimeUnit::Seconds => 1000.0,
+    TimeUnit::Millis => 1.0,
+    TimeUnit::Micros => 0.001,
+    TimeUnit::Nanos => 0.000_001,
+  }
+}
+
+fn variable<'a>(spec: &VarDelay, vars: &'a Variables) -> Result<&'a Lit, Error> {
+  vars
+    .get(&spec.var)
+    .ok_or_else(|| format_err!("Unknown variable {} in the schedule", spec.var))
+}
+
+/// The delay held by the variable: a number, or a duration such as `1m30s`.
+pub fn delay_from_var(spec: &VarDelay, vars: &Variables) -> Result<Duration, Error> {
+  let value = variable(spec, vars)?;
+  let ms = match value {
+    Lit::Str(s) => match s.trim().parse::<f64>() {
+      Ok(n) => n * unit_millis(&spec.unit),
+      Err(_) => parse_duration(s)?.num_milliseconds() as f64,
+    },
+    v => {
+      v.as_f64()
+        .ok_or_else(|| format_err!("The variable {} is not a delay: {:?}", spec.var, v))?
+        * unit_millis(&spec.unit)
+    }
+  };
+  Ok(to_duration(ms))
+}
+
+/// The delay until the date held by the variable: an RFC 3339 or RFC 2822 string, or a timestamp. A past date gives no
+/// delay.
+pub fn delay_until_var(spec: &VarDelay, vars: &Variables) -> Result<Duration, Error> {
+  let value = variable(spec, vars)?;
+  let target_ms = match value {
+    Lit::Str(s) => match s.trim().parse::<f64>() {
+      Ok(n) => n * unit_millis(&spec.unit),
+      Err(_) => time::parse_date(s)?.timestamp_millis() as f64,
+    },
+    v => {
+      v.as_f64()
+        .ok_or_else(|| format_err!("The variable {} is not a date: {:?}", spec.var, v))?
+        * unit_millis(&spec.unit)
+    }
+  };
+  Ok(to_duration(
+    target_ms - Utc::now().timestamp_millis() as f64,
+  ))
+}
+
+fn millis(duration: &str) -> Result<f64, Error> {
+  let ms = parse_duration(duration)?.num_milliseconds();
+  if ms < 0 {
+    Err(format_err!("Negative duration {}", duration))
+  } else {
+    Ok(ms as f64)
+  }
+}
+
+fn percentiles(points: &BTreeMap<String, String>) -> Result<Vec<(f64, f64)>, Error> {
+  let mut parsed = points
+    .iter()
+    .map(|(p, d)| {
+      let percentile = p
+        .parse::<f64>()
+        .ok()
+        .filter(|p| *p >= 0.0 && *p <= 100.0)
+        .ok_or_else(|| format_err!("Invalid percentile {}", p))?;
+      Ok((percentile, millis(d)?))
+    })
+    .collect::<Result<Vec<(f64, f64)>, Error>>()?;
+  if parsed.is_empty() {
+    return Err(format_err!("No percentile given"));
+  }
+  parsed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+  if parsed.windows(2).any(|w| w[1].1 < w[0].1) {
+    return Err(format_err!("Percentile durations must increase"));
+  }
+  Ok(parsed)
+}
+
+/// Linear interpolation between the given percentiles. Values below the lowest percentile (resp. above the highest) get
+/// its duration.
+fn interpolate(points: &[(f64, f64)], percentile: f64) -> f64 {
+  match points.iter().position(|(p, _)| *p >= percentile) {
+    Some(0) => points[0].1,
+    Some(i) => {
+      let ((p0, d0), (p1, d1)) = (points[i - 1], points[i]);
+      d0 + (d1 - d0) * (percentile - p0) / (p1 - p0)
+    }
+    None => points[points.len() - 1].1,
+  }
+}
+
+fn to_duration(ms: f64) -> Duration {
+  let micros = (ms.max(0.0) * 1000.0).round() as u64;
+  Duration::from_micros(micros)
+}
+
+impl DelaySpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    match self {
+      DelaySpec::Fixed(d) => millis(d).map(|_| ()),
+      DelaySpec::Uniform { min, max } => {
+        if millis(min)? > millis(max)? {
+          Err(format_err!(
+            "The minimum delay {} exceeds the maximum {}",
+            min,
+            max
+          ))
+        } else {
+          Ok(())
+        }
+      }
+      DelaySpec::Normal { mean, std_dev } => millis(mean).and(millis(std_dev)).map(|_| ()),
+      DelaySpec::Exponential { mean } => match millis(mean)? {
+        m if m > 0.0 => Ok(()),
+        _ => Err(format_err!(
+          "The mean of an exponential delay must be positive"
+        )),
+      },
+      DelaySpec::Percentiles(points) => percentiles(points).map(|_| ()),
+    }
+  }
+
+  pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<Duration, Error> {
+    let ms = match self {
+      DelaySpec::Fixed(d) => millis(d)?,
+      DelaySpec::Uniform { min, max } => {
+        let (min, max) = (millis(min)?, millis(max)?);
+        if min < max {
+          rng.gen_range(min, max)
+        } else {
+          min
+        }
+      }
+      DelaySpec::Normal { mean, std_dev } => {
+        Normal::new(millis(mean)?, millis(std_dev)?).sample(rng)
+      }
+      DelaySpec::Exponential { mean } => Exp::new(1.0 / millis(mean)?).sample(rng),
+      DelaySpec::Percentiles(points) => {
+        interpolate(&percentiles(points)?, rng.gen_range(0.0, 100.0))
+      }
+    };
+    Ok(to_duration(ms))
+  }
+}
+
+/// Checks a positive duration, such as a jitter or a bound.
+pub fn validate_duration(duration: &str) -> Result<(), Error> {
+  millis(duration).map(|_| ())
+}
+
+pub fn to_std(duration: &str) -> Result<Duration, Error> {
+  millis(duration).map(to_duration)
+}
+
+/// Adds a random duration, between `-jitter` and `+jitter`, to the delay. The result is never negative.
+pub fn jitter<R: Rng + ?Sized>(
+  delay: Duration,
+  jitter: &str,
+  rng: &mut R,
+) -> Result<Duration, Error> {
+  let amplitude = millis(jitter)?;
+  if amplitude == 0.0 {
+    return Ok(delay);
+  }
+  let base = delay.as_secs() as f64 * 1000.0 + f64::from(delay.subsec_micros()) / 1000.0;
+  Ok(to_duration(base + rng.gen_range(-amplitude, amplitude)))
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  #[test]
+  fn should_interpolate_percentiles() {
+    let points = vec![(50.0, 100.0), (90.0, 300.0), (100.0, 1000.0)];
+
+    assert_eq!(100.0, interpolate(&points, 10.0));
+    assert_eq!(200.0, interpolate(&points, 70.0));
+    assert_eq!(650.0, interpolate(&points, 95.0));
+  }
+
+  #[test]
+  fn should_sample_delays_within_bounds() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let uniform = DelaySpec::Uniform {
+      min: "100ms".to_owned(),
+      max: "1s".to_owned(),
+    };
+
+    for _ in 0..100 {
+      let delay = uniform.sample(&mut rng).unwrap();
+      assert!(delay >= Duration::from_millis(100) && delay < Duration::from_secs(1));
+      let jittered = jitter(delay, "50ms", &mut rng).unwrap();
+      assert!(jittered >= delay - Duration::from_millis(50));
+      assert!(jittered <= delay + Duration::from_millis(50));
+    }
+    assert_eq!(
+      Duration::from_millis(90_500),
+      DelaySpec::Fixed("1m30s 500ms".to_owned())
+        .sample(&mut rng)
+        .unwrap()
+    );
+  }
+
+  #[test]
+  fn should_read_delays_from_variables() {
+    let vars = hashmap! {
+      "delay".to_owned() => Lit::Int(1500),
+      "human".to_owned() => Lit::Str("1m30s".to_owned()),
+      "past".to_owned() => Lit::Str("2019-03-01T23:30:00Z".to_owned()),
+      "future".to_owned() => Lit::Int(Utc::now().timestamp() + 3600),
+    };
+    let var = |name: &str, unit: TimeUnit| VarDelay {
+      var: name.to_owned(),
+      unit,
+    };
+
+    assert_eq!(
+      Duration::from_millis(1500),
+      delay_from_var(&var("delay", TimeUnit::Millis), &vars).unwrap()
+    );
+    assert_eq!(
+      Duration::from_secs(90),
+      delay_from_var(&var("human", TimeUnit::Millis), &vars).unwrap()
+    );
+    assert_eq!(
+      Duration::from_secs(0),
+      delay_until_var(&var("past", TimeUnit::Millis), &vars).unwrap()
+    );
+    let until = delay_until_var(&var("future", TimeUnit::Seconds), &vars).unwrap();
+    assert!(until > Duration::from_secs(3590) && until <= Duration::from_secs(3600));
+    assert!(delay_from_var(&var("missing", TimeUnit::Millis), &vars).is_err());
+  }
+
+  #[test]
+  fn should_reject_invalid_delays() {
+    assert!(DelaySpec::Fixed("-1s".to_owned()).validate().is_err());
+    assert!(validate_duration("soon").is_err());
+    assert!(DelaySpec::Exponential {
+      mean: "0s".to_owned()
+    }
+    .validate()
+    .is_err());
+    assert!(DelaySpec::Percentiles(btreemap! {
+      "50".to_owned() => "1s".to_owned(),
+      "99".to_owned() => "10ms".to_owned(),
+    })
+    .validate()
+    .is_err());
+  }
+}
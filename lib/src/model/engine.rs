@@ -0,0 +1,333 @@
+use super::super::util::read_file;
+use super::time;
+use chrono::{FixedOffset, Utc};
+use failure::Error;
+use handlebars::{
+  no_escape, Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson,
+};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+pub enum TemplateSource {
+  Inline(String),
+  File(String),
+}
+
+impl TemplateSource {
+  pub fn load(&self) -> Result<String, Error> {
+    match self {
+      TemplateSource::Inline(s) => Ok(s.clone()),
+      TemplateSource::File(p) => read_file(p),
+    }
+  }
+}
+
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+pub struct HandlebarsTemplate {
+  pub template: TemplateSource,
+  /// Partial name -> path of the file holding the partial.
+  #[serde(default)]
+  pub partials: HashMap<String, String>,
+  #[serde(skip)]
+  compiled: Compiled,
+}
+
+/// The registry holding the template, its partials and the helpers, built once for all the messages.
+#[derive(Clone, Default)]
+struct Compiled(Option<Arc<Handlebars>>);
+
+/// The registry is built from the template, hence adds nothing to its identity.
+impl PartialEq for Compiled {
+  fn eq(&self, _: &Compiled) -> bool {
+    true
+  }
+}
+
+impl fmt::Debug for Compiled {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.0 {
+      Some(_) => write!(f, "Compiled"),
+      None => write!(f, "NotCompiled"),
+    }
+  }
+}
+
+const TEMPLATE_NAME: &str = "template";
+
+impl HandlebarsTemplate {
+  pub fn new(template: TemplateSource, partials: HashMap<String, String>) -> HandlebarsTemplate {
+    HandlebarsTemplate {
+      template,
+      partials,
+      compiled: Compiled::default(),
+    }
+  }
+
+  /// Reads the template and its partials, and registers them, so that they are not read again for each message.
+  pub fn compile(&mut self) -> Result<(), Error> {
+    self.compiled = Compiled(Some(Arc::new(self.registry()?)));
+    Ok(())
+  }
+
+  fn registry(&self) -> Result<Handlebars, Error> {
+    let mut registry = registry();
+    for (name, path) in self.partials.iter() {
+      registry
+        .register_partial(name, read_file(path)?)
+        .map_err(|e| format_err!("Invalid partial {}: {}", name, e))?;
+    }
+    registry
+      .register_template_string(TEMPLATE_NAME, self.template.load()?)
+      .map_err(|e| format_err!("Invalid template: {}", e))?;
+    Ok(registry)
+  }
+}
+
+type HelperFn = fn(&[JsonValue]) -> Result<JsonValue, String>;
+
+/// Adapts a plain function to a handlebars helper, usable both as `{{helper x}}` and as a
+/// subexpression `(helper x)`.
+struct SimpleHelper(HelperFn);
+
+impl HelperDef for SimpleHelper {
+  fn call_inner<'reg: 'rc, 'rc>(
+    &self,
+    h: &Helper<'reg, 'rc>,
+    _: &'reg Handlebars,
+    _: &'rc Context,
+    _: &mut RenderContext<'reg>,
+  ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+    let params: Vec<JsonValue> = h.params().iter().map(|p| p.value().clone()).collect();
+    (self.0)(&params)
+      .map(|v| Some(ScopedJson::Derived(v)))
+      .map_err(|e| RenderError::new(format!("Helper {}: {}", h.name(), e)))
+  }
+}
+
+fn as_text(value: &JsonValue) -> String {
+  match value {
+    JsonValue::String(s) => s.clone(),
+    JsonValue::Null => "".to_owned(),
+    v => v.to_string(),
+  }
+}
+
+fn param(params: &[JsonValue], idx: usize) -> Result<&JsonValue, String> {
+  params
+    .get(idx)
+    .ok_or_else(|| format!("missing parameter {}", idx + 1))
+}
+
+fn json(params: &[JsonValue]) -> Result<JsonValue, String> {
+  Ok(JsonValue::String(param(params, 0)?.to_string()))
+}
+
+fn base64(params: &[JsonValue]) -> Result<JsonValue, String> {
+  Ok(JsonValue::String(::base64::encode(&as_text(param(
+    params, 0,
+  )?))))
+}
+
+fn upper(params: &[JsonValue]) -> Result<JsonValue, String> {
+  Ok(JsonValue::String(as_text(param(params, 0)?).to_uppercase()))
+}
+
+fn lower(params: &[JsonValue]) -> Result<JsonValue, String> {
+  Ok(JsonValue::String(as_text(param(params, 0)?).to_lowercase()))
+}
+
+fn default(params: &[JsonValue]) -> Result<JsonValue, String> {
+  match param(params, 0)? {
+    JsonValue::Null => param(params, 1).map(JsonValue::clone),
+    JsonValue::String(ref s) if s.is_empty() => param(params, 1).map(JsonValue::clone),
+    v => Ok(v.clone()),
+  }
+}
+
+fn number(value: &JsonValue) -> Result<f64, String> {
+  match value {
+    JsonValue::Number(n) => n.as_f64().ok_or_else(|| format!("{} is not a number", n)),
+    JsonValue::String(s) => s
+      .trim()
+      .parse::<f64>()
+      .map_err(|_| format!("{} is not a number", s)),
+    v => Err(format!("{} is not a number", v)),
+  }
+}
+
+fn arithmetic(
+  params: &[JsonValue],
+  int_op: fn(i64, i64) -> Option<i64>,
+  real_op: fn(f64, f64) -> f64,
+) -> Result<JsonValue, String> {
+  let (a, b) = (param(params, 0)?, param(params, 1)?);
+  match (a.as_i64(), b.as_i64()) {
+    (Some(x), Some(y)) => int_op(x, y)
+      .map(JsonValue::from)
+      .ok_or_else(|| format!("cannot compute with {} and {}", x, y)),
+    _ => Ok(JsonValue::from(real_op(number(a)?, number(b)?))),
+  }
+}
+
+fn add(params: &[JsonValue]) -> Result<JsonValue, String> {
+  arithmetic(params, i64::checked_add, |x, y| x + y)
+}
+
+fn sub(params: &[JsonValue]) -> Result<JsonValue, String> {
+  arithmetic(params, i64::checked_sub, |x, y| x - y)
+}
+
+fn mul(params: &[JsonValue]) -> Result<JsonValue, String> {
+  arithmetic(params, i64::checked_mul, |x, y| x * y)
+}
+
+fn div(params: &[JsonValue]) -> Result<JsonValue, String> {
+  arithmetic(params, i64::checked_div, |x, y| x / y)
+}
+
+/// `{{date value "%Y-%m-%d" "2d"}}`: formats a date (RFC 3339/2822 string, timestamp in
+/// seconds or `"now"`), with an optional offset.
+fn date(params: &[JsonValue]) -> Result<JsonValue, String> {
+  let date = match param(params, 0)? {
+    JsonValue::String(ref s) if s == "now" => Ok(Utc::now().with_timezone(&FixedOffset::east(0))),
+    JsonValue::String(s) => time::parse_date(s).map_err(|e| e.to_string()),
+    JsonValue::Number(ref n) if n.is_i64() => {
+      time::from_timestamp(n.as_i64().unwrap()).map_err(|e| e.to_string())
+    }
+    v => Err(format!("{} is not a date", v)),
+  }?;
+  let offset = params.get(2).map(as_text);
+  let date = time::apply_offset(date, &offset).map_err(|e| e.to_string())?;
+  Ok(JsonValue::String(match params.get(1) {
+    Some(f) => {
+      let format = as_text(f);
+      time::validate_format(&format).map_err(|e| e.to_string())?;
+      date.format(&format).to_string()
+    }
+    None => date.to_rfc3339(),
+  }))
+}
+
+fn registry() -> Handlebars {
+  let mut registry = Handlebars::new();
+  registry.register_escape_fn(no_escape);
+  let helpers: [(&str, HelperFn); 10] = [
+    ("json", json),
+    ("base64", base64),
+    ("upper", upper),
+    ("lower", lower),
+    ("default", default),
+    ("add", add),
+    ("sub", sub),
+    ("mul", mul),
+    ("div", div),
+    ("date", date),
+  ];
+  for (name, helper) in helpers.iter() {
+    registry.register_helper(name, Box::new(SimpleHelper(*helper)));
+  }
+  registry
+}
+
+/// Renders the template with the registry built at load, or with a new one when it is not compiled.
+pub fn render(template: &HandlebarsTemplate, data: &JsonValue) -> Result<String, Error> {
+  let rendered = match template.compiled.0 {
+    Some(ref registry) => registry.render(TEMPLATE_NAME, data),
+    None => template.registry()?.render(TEMPLATE_NAME, data),
+  };
+  rendered.map_err(|e| format_err!("Cannot render the template: {}", e))
+}
+
+thread_local! {
+  static RAW_REGISTRY: Handlebars = registry();
+}
+
+/// Renders a template string, without escaping, with the built-in helpers.
+pub fn render_raw(template: &str, data: &JsonValue) -> Result<String, Error> {
+  RAW_REGISTRY.with(|registry| {
+    registry
+      .render_template(template, data)
+      .map_err(|e| format_err!("Cannot render the template: {}", e))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use std::{env, fs, process};
+
+  fn render_inline(template: &str, data: JsonValue) -> String {
+    render(
+      &HandlebarsTemplate::new(TemplateSource::Inline(template.to_owned()), HashMap::new()),
+      &data,
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn should_render_with_helpers() {
+    let data = json!({ "name": "Jo \"the\" <boss>", "qty": 3, "price": 1.5 });
+
+    assert_eq!(
+      r#"{"name": "Jo \"the\" <boss>", "total": 4.5, "next": 4, "code": "Sm8=", "who": "JO"}"#,
+      render_inline(
+        r#"{"name": {{json name}}, "total": {{mul qty price}}, "next": {{add qty 1}}, "code": "{{base64 "Jo"}}", "who": "{{upper (default nickname "jo")}}"}"#,
+        data
+      )
+    );
+  }
+
+  #[test]
+  fn should_format_dates() {
+    let data = json!({ "at": "2019-03-01T23:30:00Z" });
+
+    assert_eq!(
+      "2019-03-03",
+      render_inline(r#"{{date at "%Y-%m-%d" "1d 1h"}}"#, data)
+    );
+  }
+
+  #[test]
+  fn should_fail_on_an_invalid_date_format() {
+    let template = HandlebarsTemplate::new(
+      TemplateSource::Inline(r#"{{date "now" "%Q"}}"#.to_owned()),
+      HashMap::new(),
+    );
+
+    assert!(render(&template, &json!({})).is_err());
+  }
+
+  #[test]
+  fn should_read_the_template_and_its_partials_once() {
+    let dir = env::temp_dir().join(format!("lapimposteur-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let partial = dir.join("line.hbs");
+    fs::write(&partial, "{{sku}} x{{qty}}").unwrap();
+    let mut template = HandlebarsTemplate::new(
+      TemplateSource::Inline("{{#each lines}}{{> line}};{{/each}}".to_owned()),
+      hashmap! { "line".to_owned() => partial.to_string_lossy().into_owned() },
+    );
+
+    template.compile().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+      "a x1;b x2;",
+      render(
+        &template,
+        &json!({ "lines": [{ "sku": "a", "qty": 1 }, { "sku": "b", "qty": 2 }] })
+      )
+      .unwrap()
+    );
+    assert!(HandlebarsTemplate::new(
+      TemplateSource::Inline("{{#each}}".to_owned()),
+      HashMap::new()
+    )
+    .compile()
+    .is_err());
+  }
+}
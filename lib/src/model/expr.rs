@@ -0,0 +1,590 @@
+use super::imposter::{Lit, Variables};
+use failure::Error;
+use serde_json::Value as JsonValue;
+use std::cmp::Ordering;
+
+/// An expression computing a variable from the previously computed ones, e.g.
+/// `qty > 10 ? "bulk-" + upper(sku) : substr(sku, 0, 3)`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+  Lit(Lit),
+  Var(String),
+  Unary(UnaryOp, Box<Expr>),
+  Binary(BinaryOp, Box<Expr>, Box<Expr>),
+  Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+  Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOp {
+  Not,
+  Neg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+  Or,
+  And,
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Rem,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+  Int(i64),
+  Real(f64),
+  Str(String),
+  Ident(String),
+  Op(&'static str),
+}
+
+const OPERATORS: [&str; 20] = [
+  "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "!", "?", ":", "(", ")",
+  ",", "=",
+];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+  let chars: Vec<char> = input.chars().collect();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    if c.is_whitespace() {
+      i += 1;
+    } else if c.is_ascii_digit() {
+      let start = i;
+      while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+        i += 1;
+      }
+      let number: String = chars[start..i].iter().collect();
+      tokens.push(if number.contains('.') {
+        Token::Real(
+          number
+            .parse()
+            .map_err(|_| format_err!("Invalid number {} in {}", number, input))?,
+        )
+      } else {
+        Token::Int(
+          number
+            .parse()
+            .map_err(|_| format_err!("Invalid number {} in {}", number, input))?,
+        )
+      });
+    } else if c == '"' || c == '\'' {
+      let mut s = String::new();
+      i += 1;
+      loop {
+        match chars.get(i) {
+          Some(&q) if q == c => break,
+          Some('\\') => {
+            match chars.get(i + 1) {
+              Some('n') => s.push('\n'),
+              Some('t') => s.push('\t'),
+              Some(&e) => s.push(e),
+              None => return Err(format_err!("Unterminated string in {}", input)),
+            }
+            i += 2;
+          }
+          Some(&other) => {
+            s.push(other);
+            i += 1;
+          }
+          None => return Err(format_err!("Unterminated string in {}", input)),
+        }
+      }
+      i += 1;
+      tokens.push(Token::Str(s));
+    } else if c.is_alphabetic() || c == '_' {
+      let start = i;
+      while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+        i += 1;
+      }
+      tokens.push(Token::Ident(chars[start..i].iter().collect()));
+    } else {
+      let rest: String = chars[i..].iter().take(2).collect();
+      let op = OPERATORS
+        .iter()
+        .find(|op| rest.starts_with(*op))
+        .ok_or_else(|| format_err!("Unexpected character {} in {}", c, input))?;
+      if *op == "=" {
+        return Err(format_err!("Unexpected = in {}, use == to compare", input));
+      }
+      tokens.push(Token::Op(op));
+      i += op.len();
+    }
+  }
+  Ok(tokens)
+}
+
+struct Parser<'a> {
+  input: &'a str,
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn peek_op(&self, op: &str) -> bool {
+    match self.tokens.get(self.pos) {
+      Some(Token::Op(o)) => *o == op,
+      _ => false,
+    }
+  }
+
+  fn eat_op(&mut self, op: &str) -> bool {
+    let found = self.peek_op(op);
+    if found {
+      self.pos += 1;
+    }
+    found
+  }
+
+  fn expect_op(&mut self, op: &str) -> Result<(), Error> {
+    if self.eat_op(op) {
+      Ok(())
+    } else {
+      Err(format_err!("Expected {} in {}", op, self.input))
+    }
+  }
+
+  fn conditional(&mut self) -> Result<Expr, Error> {
+    let condition = self.binary(0)?;
+    if self.eat_op("?") {
+      let then = self.conditional()?;
+      self.expect_op(":")?;
+      let otherwise = self.conditional()?;
+      Ok(Expr::Cond(
+        Box::new(condition),
+        Box::new(then),
+        Box::new(otherwise),
+      ))
+    } else {
+      Ok(condition)
+    }
+  }
+
+  fn binary(&mut self, level: usize) -> Result<Expr, Error> {
+    const LEVELS: [&[(&str, BinaryOp)]; 5] = [
+      &[("||", BinaryOp::Or)],
+      &[("&&", BinaryOp::And)],
+      &[
+        ("==", BinaryOp::Eq),
+        ("!=", BinaryOp::Ne),
+        ("<=", BinaryOp::Le),
+        (">=", BinaryOp::Ge),
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+      ],
+      &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+      &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Rem),
+      ],
+    ];
+    if level == LEVELS.len() {
+      return self.unary();
+    }
+    let mut left = self.binary(level + 1)?;
+    while let Some(&(_, op)) = LEVELS[level].iter().find(|(s, _)| self.peek_op(s)) {
+      self.pos += 1;
+      let right = self.binary(level + 1)?;
+      left = Expr::Binary(op, Box::new(left), Box::new(right));
+    }
+    Ok(left)
+  }
+
+  fn unary(&mut self) -> Result<Expr, Error> {
+    if self.eat_op("!") {
+      Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?)))
+    } else if self.eat_op("-") {
+      Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?)))
+    } else {
+      self.primary()
+    }
+  }
+
+  fn primary(&mut self) -> Result<Expr, Error> {
+    let token = self
+      .tokens
+      .get(self.pos)
+      .cloned()
+      .ok_or_else(|| format_err!("Unexpected end of expression {}", self.input))?;
+    self.pos += 1;
+    match token {
+      Token::Int(i) => Ok(Expr::Lit(Lit::Int(i))),
+      Token::Real(r) => Ok(Expr::Lit(Lit::Real(r))),
+      Token::Str(s) => Ok(Expr::Lit(Lit::Str(s))),
+      Token::Ident(ref name) if name == "true" => Ok(Expr::Lit(Lit::Bool(true))),
+      Token::Ident(ref name) if name == "false" => Ok(Expr::Lit(Lit::Bool(false))),
+      Token::Ident(ref name) if name == "null" => Ok(Expr::Lit(Lit::Null)),
+      Token::Ident(name) => {
+        if self.eat_op("(") {
+          let mut args = Vec::new();
+          if !self.eat_op(")") {
+            loop {
+              args.push(self.conditional()?);
+              if self.eat_op(")") {
+                break;
+              }
+              self.expect_op(",")?;
+            }
+          }
+          Ok(Expr::Call(name, args))
+        } else {
+          Ok(Expr::Var(name))
+        }
+      }
+      Token::Op("(") => {
+        let inner = self.conditional()?;
+        self.expect_op(")")?;
+        Ok(inner)
+      }
+      Token::Op(op) => Err(format_err!("Unexpected {} in {}", op, self.input)),
+    }
+  }
+}
+
+pub fn parse(input: &str) -> Result<Expr, Error> {
+  let mut parser = Parser {
+    input,
+    tokens: tokenize(input)?,
+    pos: 0,
+  };
+  let expr = parser.conditional()?;
+  match parser.tokens.get(parser.pos) {
+    None => Ok(expr),
+    Some(token) => Err(format_err!("Unexpected {:?} in {}", token, input)),
+  }
+}
+
+/// Unwraps json scalars, so that they can be used like other literals.
+fn scalar(value: &Lit) -> Lit {
+  match value {
+    Lit::Json(JsonValue::Number(n)) => n
+      .as_i64()
+      .map(Lit::Int)
+      .or_else(|| n.as_f64().map(Lit::Real))
+      .unwrap_or(Lit::Null),
+    Lit::Json(JsonValue::String(s)) => Lit::Str(s.clone()),
+    Lit::Json(JsonValue::Bool(b)) => Lit::Bool(*b),
+    Lit::Json(JsonValue::Null) => Lit::Null,
+    v => v.clone(),
+  }
+}
+
+fn as_bool(value: &Lit) -> Result<bool, Error> {
+  match value {
+    Lit::Bool(b) => Ok(*b),
+    v => Err(format_err!("{:?} is not a boolean", v)),
+  }
+}
+
+fn as_str(value: &Lit) -> String {
+  String::from(value.clone())
+}
+
+fn as_int(value: &Lit) -> Result<i64, Error> {
+  match value {
+    Lit::Str(s) => s
+      .trim()
+      .parse()
+      .map_err(|_| format_err!("{} is not an integer", s)),
+    v => v
+      .as_i64()
+      .or_else(|| v.as_f64().map(|r| r as i64))
+      .ok_or_else(|| format_err!("{:?} is not an integer", v)),
+  }
+}
+
+fn as_real(value: &Lit) -> Result<f64, Error> {
+  match value {
+    Lit::Str(s) => s
+      .trim()
+      .parse()
+      .map_err(|_| format_err!("{} is not a number", s)),
+    v => v
+      .as_f64()
+      .ok_or_else(|| format_err!("{:?} is not a number", v)),
+  }
+}
+
+fn is_number(value: &Lit) -> bool {
+  match value {
+    Lit::Str(_) | Lit::Bool(_) | Lit::Null => false,
+    v => v.as_f64().is_some(),
+  }
+}
+
+fn compare(left: &Lit, right: &Lit) -> Result<Ordering, Error> {
+  match (left, right) {
+    (Lit::Int(a), Lit::Int(b)) => Ok(a.cmp(b)),
+    (a, b) if is_number(a) && is_number(b) => as_real(a)?
+      .partial_cmp(&as_real(b)?)
+      .ok_or_else(|| format_err!("Cannot compare {:?} and {:?}", a, b)),
+    (Lit::Str(a), Lit::Str(b)) => Ok(a.cmp(b)),
+    (a, b) => Err(format_err!("Cannot compare {:?} and {:?}", a, b)),
+  }
+}
+
+fn equals(left: &Lit, right: &Lit) -> bool {
+  if is_number(left) && is_number(right) {
+    compare(left, right).ok() == Some(Ordering::Equal)
+  } else {
+    left.to_json() == right.to_json()
+  }
+}
+
+fn arithmetic(op: BinaryOp, left: &Lit, right: &Lit) -> Result<Lit, Error> {
+  let overflow = || format_err!("Overflow computing {:?} {:?} {:?}", left, op, right);
+  match (left.as_i64(), right.as_i64()) {
+    (Some(a), Some(b)) => match op {
+      BinaryOp::Add => a.checked_add(b),
+      BinaryOp::Sub => a.checked_sub(b),
+      BinaryOp::Mul => a.checked_mul(b),
+      BinaryOp::Div => a.checked_div(b),
+      _ => a.checked_rem(b),
+    }
+    .map(Lit::Int)
+    .ok_or_else(overflow),
+    _ => {
+      let (a, b) = (as_real(left)?, as_real(right)?);
+      Ok(Lit::Real(match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mul => a * b,
+        BinaryOp::Div => a / b,
+        _ => a % b,
+      }))
+    }
+  }
+}
+
+/// 64-bit FNV-1a, stable across runs and platforms.
+fn fnv1a(s: &str) -> u64 {
+  s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+    (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+  })
+}
+
+fn call(name: &str, args: &[Lit]) -> Result<Lit, Error> {
+  let arity = |n: usize| {
+    if args.len() == n {
+      Ok(())
+    } else {
+      Err(format_err!(
+        "{} expects {} argument(s), got {}",
+        name,
+        n,
+        args.len()
+      ))
+    }
+  };
+  match name {
+    "len" => {
+      arity(1)?;
+      Ok(Lit::Int(match &args[0] {
+        Lit::Json(JsonValue::Array(a)) => a.len(),
+        Lit::Json(JsonValue::Object(o)) => o.len(),
+        v => as_str(v).chars().count(),
+      } as i64))
+    }
+    "substr" => {
+      if args.len() != 2 && args.len() != 3 {
+        return Err(format_err!("substr expects 2 or 3 arguments"));
+      }
+      let s = as_str(&args[0]);
+      let start = as_int(&args[1])?.max(0) as usize;
+      let chars = s.chars().skip(start);
+      Ok(Lit::Str(match args.get(2) {
+        Some(len) => chars.take(as_int(len)?.max(0) as usize).collect(),
+        None => chars.collect(),
+      }))
+    }
+    "hash" => {
+      arity(1)?;
+      Ok(Lit::Str(format!("{:016x}", fnv1a(&as_str(&args[0])))))
+    }
+    "upper" => {
+      arity(1)?;
+      Ok(Lit::Str(as_str(&args[0]).to_uppercase()))
+    }
+    "lower" => {
+      arity(1)?;
+      Ok(Lit::Str(as_str(&args[0]).to_lowercase()))
+    }
+    "trim" => {
+      arity(1)?;
+      Ok(Lit::Str(as_str(&args[0]).trim().to_owned()))
+    }
+    "contains" => {
+      arity(2)?;
+      Ok(Lit::Bool(as_str(&args[0]).contains(&as_str(&args[1]))))
+    }
+    "str" => {
+      arity(1)?;
+      Ok(Lit::Str(as_str(&args[0])))
+    }
+    "int" => {
+      arity(1)?;
+      as_int(&args[0]).map(Lit::Int)
+    }
+    "real" => {
+      arity(1)?;
+      as_real(&args[0]).map(Lit::Real)
+    }
+    "abs" => {
+      arity(1)?;
+      match &args[0] {
+        Lit::Int(i) => i
+          .checked_abs()
+          .map(Lit::Int)
+          .ok_or_else(|| format_err!("Overflow computing abs({})", i)),
+        v => as_real(v).map(|r| Lit::Real(r.abs())),
+      }
+    }
+    "min" | "max" => {
+      if args.is_empty() {
+        return Err(format_err!("{} expects at least 1 argument", name));
+      }
+      args[1..].iter().fold(Ok(args[0].clone()), |acc, arg| {
+        let best = acc?;
+        let ordering = compare(arg, &best)?;
+        let better = if name == "min" {
+          ordering == Ordering::Less
+        } else {
+          ordering == Ordering::Greater
+        };
+        Ok(if better { arg.clone() } else { best })
+      })
+    }
+    _ => Err(format_err!("Unknown function {}", name)),
+  }
+}
+
+impl Expr {
+  /// The variables referenced by the expression.
+  pub fn variables(&self) -> Vec<String> {
+    match self {
+      Expr::Lit(_) => vec![],
+      Expr::Var(name) => vec![name.clone()],
+      Expr::Unary(_, e) => e.variables(),
+      Expr::Binary(_, l, r) => [l, r].iter().flat_map(|e| e.variables()).collect(),
+      Expr::Cond(c, t, o) => [c, t, o].iter().flat_map(|e| e.variables()).collect(),
+      Expr::Call(_, args) => args.iter().flat_map(Expr::variables).collect(),
+    }
+  }
+
+  /// Evaluates a condition.
+  pub fn eval_bool(&self, vars: &Variables) -> Result<bool, Error> {
+    as_bool(&scalar(&self.eval(vars)?))
+  }
+
+  pub fn eval(&self, vars: &Variables) -> Result<Lit, Error> {
+    match self {
+      Expr::Lit(l) => Ok(l.clone()),
+      Expr::Var(name) => vars
+        .get(name)
+        .map(scalar)
+        .ok_or_else(|| format_err!("Unknown variable {}", name)),
+      Expr::Unary(UnaryOp::Not, e) => Ok(Lit::Bool(!as_bool(&e.eval(vars)?)?)),
+      Expr::Unary(UnaryOp::Neg, e) => arithmetic(BinaryOp::Sub, &Lit::Int(0), &e.eval(vars)?),
+      Expr::Binary(BinaryOp::Or, l, r) => Ok(Lit::Bool(
+        as_bool(&l.eval(vars)?)? || as_bool(&r.eval(vars)?)?,
+      )),
+      Expr::Binary(BinaryOp::And, l, r) => Ok(Lit::Bool(
+        as_bool(&l.eval(vars)?)? && as_bool(&r.eval(vars)?)?,
+      )),
+      Expr::Binary(op, l, r) => {
+        let (left, right) = (l.eval(vars)?, r.eval(vars)?);
+        match op {
+          BinaryOp::Eq => Ok(Lit::Bool(equals(&left, &right))),
+          BinaryOp::Ne => Ok(Lit::Bool(!equals(&left, &right))),
+          BinaryOp::Lt => Ok(Lit::Bool(compare(&left, &right)? == Ordering::Less)),
+          BinaryOp::Le => Ok(Lit::Bool(compare(&left, &right)? != Ordering::Greater)),
+          BinaryOp::Gt => Ok(Lit::Bool(compare(&left, &right)? == Ordering::Greater)),
+          BinaryOp::Ge => Ok(Lit::Bool(compare(&left, &right)? != Ordering::Less)),
+          BinaryOp::Add if !is_number(&left) || !is_number(&right) => {
+            Ok(Lit::Str(as_str(&left) + &as_str(&right)))
+          }
+          op => arithmetic(*op, &left, &right),
+        }
+      }
+      Expr::Cond(c, t, o) => {
+        if as_bool(&c.eval(vars)?)? {
+          t.eval(vars)
+        } else {
+          o.eval(vars)
+        }
+      }
+      Expr::Call(name, args) => {
+        let values = args
+          .iter()
+          .map(|a| a.eval(vars))
+          .collect::<Result<Vec<Lit>, Error>>()?;
+        call(name, &values)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  fn eval(input: &str) -> Lit {
+    let vars = hashmap! {
+      "qty".to_owned() => Lit::Int(12),
+      "price".to_owned() => Lit::Real(2.5),
+      "sku".to_owned() => Lit::Str("abc-123".to_owned()),
+      "items".to_owned() => Lit::Json(json!([1, 2, 3])),
+    };
+    parse(input).unwrap().eval(&vars).unwrap()
+  }
+
+  #[test]
+  fn should_respect_precedence() {
+    assert_eq!(Lit::Int(7), eval("1 + 2 * 3"));
+    assert_eq!(Lit::Int(9), eval("(1 + 2) * 3"));
+    assert_eq!(Lit::Real(30.0), eval("qty * price"));
+    assert_eq!(Lit::Int(2), eval("qty % 5"));
+    assert_eq!(Lit::Int(-10), eval("-qty + 2"));
+  }
+
+  #[test]
+  fn should_evaluate_conditionals_and_strings() {
+    assert_eq!(
+      Lit::Str("bulk-ABC".to_owned()),
+      eval(r#"qty > 10 && len(items) == 3 ? "bulk-" + upper(substr(sku, 0, 3)) : sku"#)
+    );
+    assert_eq!(Lit::Str("sku abc-123".to_owned()), eval("'sku ' + sku"));
+    assert_eq!(Lit::Bool(true), eval("!(price >= 3) || false"));
+    assert_eq!(Lit::Str("123".to_owned()), eval("substr(sku, 4)"));
+    assert_eq!(eval("hash(sku)"), eval("hash('abc-' + 123)"));
+  }
+
+  #[test]
+  fn should_list_the_referenced_variables() {
+    assert_eq!(
+      vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+      parse("a > 1 ? len(b) : c + 1").unwrap().variables()
+    );
+  }
+
+  #[test]
+  fn should_reject_invalid_expressions() {
+    assert!(parse("1 +").is_err());
+    assert!(parse("a = 1").is_err());
+    assert!(parse("(a").is_err());
+    assert!(parse("a b").is_err());
+  }
+}
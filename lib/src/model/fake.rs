@@ -0,0 +1,317 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct FakeSpec {
+  pub kind: FakeKind,
+  #[serde(default)]
+  pub locale: Locale,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum FakeKind {
+  FirstName,
+  LastName,
+  Name,
+  Email,
+  Address,
+  City,
+  PhoneNumber,
+  Iban,
+  CompanyName,
+  CountryCode,
+  Lorem(u8),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum Locale {
+  #[serde(rename = "en")]
+  En,
+  #[serde(rename = "fr")]
+  Fr,
+}
+
+impl Default for Locale {
+  fn default() -> Locale {
+    Locale::En
+  }
+}
+
+struct Dictionary {
+  first_names: &'static [&'static str],
+  last_names: &'static [&'static str],
+  streets: &'static [&'static str],
+  cities: &'static [&'static str],
+  company_suffixes: &'static [&'static str],
+  email_domains: &'static [&'static str],
+  iban_country: &'static str,
+  iban_bban_length: usize,
+}
+
+const EN: Dictionary = Dictionary {
+  first_names: &[
+    "James",
+    "Mary",
+    "John",
+    "Patricia",
+    "Robert",
+    "Jennifer",
+    "Michael",
+    "Linda",
+    "William",
+    "Elizabeth",
+    "David",
+    "Susan",
+    "Richard",
+    "Jessica",
+    "Thomas",
+    "Sarah",
+  ],
+  last_names: &[
+    "Smith", "Johnson", "Williams", "Brown", "Jones", "Miller", "Davis", "Wilson", "Anderson",
+    "Taylor", "Thomas", "Moore", "Martin", "Jackson", "White", "Harris",
+  ],
+  streets: &[
+    "Main Street",
+    "Oak Avenue",
+    "Park Lane",
+    "Church Road",
+    "Mill Street",
+    "Station Road",
+    "Victoria Road",
+    "Green Lane",
+  ],
+  cities: &[
+    "London",
+    "Manchester",
+    "Bristol",
+    "Leeds",
+    "Liverpool",
+    "Edinburgh",
+    "Cardiff",
+    "Oxford",
+  ],
+  company_suffixes: &["Ltd", "Inc", "LLC", "Group", "& Sons", "Holdings"],
+  email_domains: &["example.com", "example.org", "example.net"],
+  iban_country: "GB",
+  iban_bban_length: 18,
+};
+
+const FR: Dictionary = Dictionary {
+  first_names: &[
+    "Jean",
+    "Marie",
+    "Pierre",
+    "Nathalie",
+    "Michel",
+    "Isabelle",
+    "Philippe",
+    "Sylvie",
+    "Alain",
+    "Catherine",
+    "Nicolas",
+    "Sophie",
+    "Christophe",
+    "Camille",
+    "Julien",
+    "Chantal",
+  ],
+  last_names: &[
+    "Martin", "Bernard", "Dubois", "Thomas", "Robert", "Richard", "Petit", "Durand", "Leroy",
+    "Moreau", "Simon", "Laurent", "Lefebvre", "Michel", "Garcia", "Delmas",
+  ],
+  streets: &[
+    "rue de la Paix",
+    "avenue Victor Hugo",
+    "boulevard Voltaire",
+    "rue du Moulin",
+    "place de l'Église",
+    "rue des Écoles",
+    "chemin des Vignes",
+    "allée des Tilleuls",
+  ],
+  cities: &[
+    "Paris",
+    "Lyon",
+    "Marseille",
+    "Bordeaux",
+    "Toulouse",
+    "Nantes",
+    "Lille",
+    "Strasbourg",
+  ],
+  company_suffixes: &["SA", "SARL", "SAS", "et Fils", "Groupe"],
+  email_domains: &["exemple.fr", "exemple.com", "exemple.org"],
+  iban_country: "FR",
+  iban_bban_length: 23,
+};
+
+const COUNTRY_CODES: &[&str] = &[
+  "FR", "GB", "DE", "ES", "IT", "BE", "NL", "CH", "PT", "US", "CA", "BR", "CN", "JP", "IN", "AU",
+];
+
+const LOREM: &[&str] = &[
+  "lorem",
+  "ipsum",
+  "dolor",
+  "sit",
+  "amet",
+  "consectetur",
+  "adipiscing",
+  "elit",
+  "sed",
+  "do",
+  "eiusmod",
+  "tempor",
+  "incididunt",
+  "ut",
+  "labore",
+  "et",
+  "dolore",
+  "magna",
+  "aliqua",
+];
+
+fn dictionary(locale: &Locale) -> &'static Dictionary {
+  match locale {
+    Locale::En => &EN,
+    Locale::Fr => &FR,
+  }
+}
+
+fn pick<R: Rng + ?Sized>(rng: &mut R, words: &'static [&'static str]) -> &'static str {
+  words.choose(rng).unwrap_or(&"")
+}
+
+fn digits<R: Rng + ?Sized>(rng: &mut R, count: usize) -> String {
+  (0..count)
+    .map(|_| (b'0' + rng.gen_range(0, 10)) as char)
+    .collect()
+}
+
+fn ascii_lowercase(s: &str) -> String {
+  s.chars()
+    .map(|c| match c {
+      'é' | 'è' | 'ê' => 'e',
+      'ç' => 'c',
+      c => c,
+    })
+    .filter(|c| c.is_ascii_alphanumeric())
+    .collect::<String>()
+    .to_ascii_lowercase()
+}
+
+fn iban_check_digits(country: &str, bban: &str) -> u32 {
+  let rearranged = format!("{}{}00", bban, country);
+  let remainder = rearranged.chars().fold(0u32, |acc, c| {
+    let value = c.to_digit(36).unwrap_or(0);
+    if value >= 10 {
+      (acc * 100 + value) % 97
+    } else {
+      (acc * 10 + value) % 97
+    }
+  });
+  98 - remainder
+}
+
+fn iban<R: Rng + ?Sized>(rng: &mut R, dict: &Dictionary) -> String {
+  let bban = digits(rng, dict.iban_bban_length);
+  let check = iban_check_digits(dict.iban_country, &bban);
+  format!("{}{:02}{}", dict.iban_country, check, bban)
+}
+
+fn phone_number<R: Rng + ?Sized>(rng: &mut R, locale: &Locale) -> String {
+  match locale {
+    Locale::En => format!("+44 7{} {}", digits(rng, 3), digits(rng, 6)),
+    Locale::Fr => format!(
+      "+33 6 {} {} {} {}",
+      digits(rng, 2),
+      digits(rng, 2),
+      digits(rng, 2),
+      digits(rng, 2)
+    ),
+  }
+}
+
+fn address<R: Rng + ?Sized>(rng: &mut R, locale: &Locale) -> String {
+  let dict = dictionary(locale);
+  let number = rng.gen_range(1, 200);
+  let street = pick(rng, dict.streets);
+  let city = pick(rng, dict.cities);
+  match locale {
+    Locale::En => format!(
+      "{} {}, {} {}{} {}",
+      number,
+      street,
+      city,
+      (b'A' + rng.gen_range(0, 26)) as char,
+      digits(rng, 1),
+      digits(rng, 1)
+    ),
+    Locale::Fr => format!("{}, {}, {} {}", number, street, digits(rng, 5), city),
+  }
+}
+
+fn lorem<R: Rng + ?Sized>(rng: &mut R, words: u8) -> String {
+  (0..words)
+    .map(|_| pick(rng, LOREM))
+    .collect::<Vec<&str>>()
+    .join(" ")
+}
+
+pub fn generate<R: Rng + ?Sized>(rng: &mut R, spec: &FakeSpec) -> String {
+  let dict = dictionary(&spec.locale);
+  match spec.kind {
+    FakeKind::FirstName => pick(rng, dict.first_names).to_owned(),
+    FakeKind::LastName => pick(rng, dict.last_names).to_owned(),
+    FakeKind::Name => format!(
+      "{} {}",
+      pick(rng, dict.first_names),
+      pick(rng, dict.last_names)
+    ),
+    FakeKind::Email => format!(
+      "{}.{}{}@{}",
+      ascii_lowercase(pick(rng, dict.first_names)),
+      ascii_lowercase(pick(rng, dict.last_names)),
+      rng.gen_range(1, 100),
+      pick(rng, dict.email_domains)
+    ),
+    FakeKind::Address => address(rng, &spec.locale),
+    FakeKind::City => pick(rng, dict.cities).to_owned(),
+    FakeKind::PhoneNumber => phone_number(rng, &spec.locale),
+    FakeKind::Iban => iban(rng, dict),
+    FakeKind::CompanyName => format!(
+      "{} {}",
+      pick(rng, dict.last_names),
+      pick(rng, dict.company_suffixes)
+    ),
+    FakeKind::CountryCode => pick(rng, COUNTRY_CODES).to_owned(),
+    FakeKind::Lorem(words) => lorem(rng, words),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  #[test]
+  fn iban_should_have_valid_check_digits() {
+    assert_eq!(76, iban_check_digits("FR", "30006000011234567890189"));
+  }
+
+  #[test]
+  fn generated_values_should_honor_the_seed() {
+    let spec = FakeSpec {
+      kind: FakeKind::Email,
+      locale: Locale::Fr,
+    };
+
+    let first = generate(&mut StdRng::seed_from_u64(42), &spec);
+    let second = generate(&mut StdRng::seed_from_u64(42), &spec);
+
+    assert_eq!(first, second);
+  }
+}
@@ -0,0 +1,394 @@
+use super::delay::{self, DelaySpec};
+use super::imposter::Message;
+use failure::Error;
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Faults to inject in the published messages, each with its own probability.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct FaultsSpec {
+  /// The message is not published.
+  #[serde(default)]
+  pub drop: Option<Chance>,
+  #[serde(default)]
+  pub duplicate: Option<Duplicate>,
+  #[serde(default)]
+  pub reorder: Option<Reorder>,
+  /// The payload is cut at a random length.
+  #[serde(default)]
+  pub truncate: Option<Chance>,
+  #[serde(default)]
+  pub corrupt: Option<Corrupt>,
+  #[serde(default)]
+  pub omit_headers: Option<OmitHeaders>,
+  #[serde(default)]
+  pub spike: Option<Spike>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Chance {
+  #[serde(default = "always")]
+  pub probability: f64,
+}
+
+/// The message is published `copies` more times.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Duplicate {
+  #[serde(default = "always")]
+  pub probability: f64,
+  #[serde(default = "one")]
+  pub copies: u32,
+}
+
+/// The message is held for a random duration below `window`, in the background: the next messages can overtake it.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Reorder {
+  #[serde(default = "always")]
+  pub probability: f64,
+  pub window: String,
+}
+
+/// `bytes` random bytes of the payload are altered.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Corrupt {
+  #[serde(default = "always")]
+  pub probability: f64,
+  #[serde(default = "one_byte")]
+  pub bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct OmitHeaders {
+  #[serde(default = "always")]
+  pub probability: f64,
+  pub headers: Vec<String>,
+}
+
+/// The message is published after an additional delay.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Spike {
+  #[serde(default = "always")]
+  pub probability: f64,
+  pub delay: DelaySpec,
+}
+
+fn always() -> f64 {
+  1.0
+}
+
+fn one() -> u32 {
+  1
+}
+
+fn one_byte() -> usize {
+  1
+}
+
+/// A fault injected in a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+  Dropped,
+  Duplicated(u32),
+  Reordered(Duration),
+  Truncated(usize),
+  Corrupted(usize),
+  HeadersOmitted(Vec<String>),
+  Spiked(Duration),
+}
+
+impl Fault {
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Fault::Dropped => "drop",
+      Fault::Duplicated(_) => "duplicate",
+      Fault::Reordered(_) => "reorder",
+      Fault::Truncated(_) => "truncate",
+      Fault::Corrupted(_) => "corrupt",
+      Fault::HeadersOmitted(_) => "omit_headers",
+      Fault::Spiked(_) => "spike",
+    }
+  }
+}
+
+impl fmt::Display for Fault {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Fault::Dropped => write!(f, "message dropped"),
+      Fault::Duplicated(n) => write!(f, "message published {} more time(s)", n),
+      Fault::Reordered(d) => write!(f, "message held {:?} in the background", d),
+      Fault::Truncated(len) => write!(f, "payload truncated to {} bytes", len),
+      Fault::Corrupted(n) => write!(f, "{} byte(s) of the payload corrupted", n),
+      Fault::HeadersOmitted(h) => write!(f, "headers {} omitted", h.join(", ")),
+      Fault::Spiked(d) => write!(f, "message delayed {:?}", d),
+    }
+  }
+}
+
+/// A message to publish, once the faults are injected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Publication {
+  pub message: Message,
+  pub delay: Duration,
+  /// Whether the next messages can be published before this one.
+  pub background: bool,
+}
+
+/// The number of injected faults, by kind.
+#[derive(Debug, Default)]
+pub struct FaultCounters(Mutex<BTreeMap<&'static str, usize>>);
+
+impl FaultCounters {
+  /// Counts the fault, and returns the number of faults of its kind so far.
+  pub fn record(&self, fault: &Fault) -> usize {
+    let mut counters = self.0.lock().unwrap();
+    let count = counters.entry(fault.kind()).or_insert(0);
+    *count += 1;
+    *count
+  }
+
+  pub fn get(&self, kind: &str) -> usize {
+    self.0.lock().unwrap().get(kind).cloned().unwrap_or(0)
+  }
+}
+
+fn validate_probability(name: &str, probability: f64) -> Result<(), Error> {
+  if probability >= 0.0 && probability <= 1.0 {
+    Ok(())
+  } else {
+    Err(format_err!(
+      "The probability of {} must be between 0 and 1, not {}",
+      name,
+      probability
+    ))
+  }
+}
+
+fn happens<R: Rng + ?Sized>(probability: f64, rng: &mut R) -> bool {
+  probability > 0.0 && rng.gen_bool(probability.min(1.0))
+}
+
+impl FaultsSpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    let probabilities = [
+      ("drop", self.drop.as_ref().map(|f| f.probability)),
+      ("duplicate", self.duplicate.as_ref().map(|f| f.probability)),
+      ("reorder", self.reorder.as_ref().map(|f| f.probability)),
+      ("truncate", self.truncate.as_ref().map(|f| f.probability)),
+      ("corrupt", self.corrupt.as_ref().map(|f| f.probability)),
+      (
+        "omit_headers",
+        self.omit_headers.as_ref().map(|f| f.probability),
+      ),
+      ("spike", self.spike.as_ref().map(|f| f.probability)),
+    ];
+    for (name, probability) in probabilities.iter() {
+      if let Some(p) = probability {
+        validate_probability(name, *p)?;
+      }
+    }
+    if let Some(ref reorder) = self.reorder {
+      delay::validate_duration(&reorder.window)?;
+    }
+    if let Some(ref spike) = self.spike {
+      spike.delay.validate()?;
+    }
+    Ok(())
+  }
+
+  /// Decides the faults to inject in the message: the resulting messages to publish (none when it is dropped), along
+  /// with the injected faults.
+  pub fn inject<R: Rng + ?Sized>(
+    &self,
+    mut message: Message,
+    rng: &mut R,
+  ) -> Result<(Vec<Publication>, Vec<Fault>), Error> {
+    let mut faults = vec![];
+    if self
+      .drop
+      .as_ref()
+      .map_or(false, |f| happens(f.probability, rng))
+    {
+      return Ok((vec![], vec![Fault::Dropped]));
+    }
+    if let Some(ref omit) = self.omit_headers {
+      if happens(omit.probability, rng) {
+        let omitted: Vec<String> = omit
+          .headers
+          .iter()
+          .filter(|h| message.headers.remove(*h).is_some())
+          .cloned()
+          .collect();
+        if !omitted.is_empty() {
+          faults.push(Fault::HeadersOmitted(omitted));
+        }
+      }
+    }
+    let truncated = self
+      .truncate
+      .as_ref()
+      .map_or(false, |f| happens(f.probability, rng));
+    if truncated && !message.payload.is_empty() {
+      let len = rng.gen_range(0, message.payload.len());
+      message.payload.truncate(len);
+      faults.push(Fault::Truncated(len));
+    }
+    if let Some(ref corrupt) = self.corrupt {
+      if !message.payload.is_empty() && corrupt.bytes > 0 && happens(corrupt.probability, rng) {
+        for _ in 0..corrupt.bytes {
+          let i = rng.gen_range(0, message.payload.len());
+          message.payload[i] ^= rng.gen_range(1, 256) as u8;
+        }
+        faults.push(Fault::Corrupted(corrupt.bytes));
+      }
+    }
+    let mut delay = Duration::from_secs(0);
+    if let Some(ref spike) = self.spike {
+      if happens(spike.probability, rng) {
+        let extra = spike.delay.sample(rng)?;
+        delay += extra;
+        faults.push(Fault::Spiked(extra));
+      }
+    }
+    let mut background = false;
+    if let Some(ref reorder) = self.reorder {
+      if happens(reorder.probability, rng) {
+        let window = delay::to_std(&reorder.window)?;
+        let window_micros = window.as_secs() * 1_000_000 + u64::from(window.subsec_micros());
+        let held = Duration::from_micros((window_micros as f64 * rng.gen_range(0.0, 1.0)) as u64);
+        delay += held;
+        background = true;
+        faults.push(Fault::Reordered(held));
+      }
+    }
+    let copies = match self.duplicate {
+      Some(ref duplicate) if happens(duplicate.probability, rng) => {
+        faults.push(Fault::Duplicated(duplicate.copies));
+        duplicate.copies
+      }
+      _ => 0,
+    };
+    let publication = Publication {
+      message,
+      delay,
+      background,
+    };
+    Ok((vec![publication; copies as usize + 1], faults))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::super::imposter::{Headers, JsonBody, Lit, Route};
+  use super::*;
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  fn message() -> Message {
+    let mut headers = Headers::new();
+    headers.insert("message_id".to_owned(), Lit::Str("m-1".to_owned()));
+    headers.insert(
+      "content_type".to_owned(),
+      Lit::Str("application/json".to_owned()),
+    );
+    Message {
+      payload: br#"{ "id": 7 }"#.to_vec(),
+      headers,
+      route: Route {
+        exchange: "x".to_owned(),
+        routing_key: "r.k".to_owned(),
+      },
+      delivery: None,
+      json_body: JsonBody::default(),
+    }
+  }
+
+  fn certain() -> Chance {
+    Chance { probability: 1.0 }
+  }
+
+  #[test]
+  fn should_drop_the_message() {
+    let faults = FaultsSpec {
+      drop: Some(certain()),
+      duplicate: Some(Duplicate {
+        probability: 1.0,
+        copies: 2,
+      }),
+      ..FaultsSpec::default()
+    };
+
+    let (publications, injected) = faults
+      .inject(message(), &mut StdRng::seed_from_u64(42))
+      .unwrap();
+
+    assert!(publications.is_empty());
+    assert_eq!(vec![Fault::Dropped], injected);
+  }
+
+  #[test]
+  fn should_alter_and_duplicate_the_message() {
+    let faults = FaultsSpec {
+      duplicate: Some(Duplicate {
+        probability: 1.0,
+        copies: 2,
+      }),
+      corrupt: Some(Corrupt {
+        probability: 1.0,
+        bytes: 1,
+      }),
+      omit_headers: Some(OmitHeaders {
+        probability: 1.0,
+        headers: vec!["message_id".to_owned(), "missing".to_owned()],
+      }),
+      spike: Some(Spike {
+        probability: 1.0,
+        delay: DelaySpec::Fixed("2s".to_owned()),
+      }),
+      ..FaultsSpec::default()
+    };
+
+    let (publications, injected) = faults
+      .inject(message(), &mut StdRng::seed_from_u64(42))
+      .unwrap();
+
+    assert_eq!(3, publications.len());
+    let published = &publications[0];
+    assert_eq!(Duration::from_secs(2), published.delay);
+    assert!(!published.background);
+    assert_eq!(None, published.message.headers.get("message_id"));
+    assert!(published.message.headers.get("content_type").is_some());
+    assert_eq!(message().payload.len(), published.message.payload.len());
+    assert_ne!(message().payload, published.message.payload);
+    assert_eq!(
+      vec!["omit_headers", "corrupt", "spike", "duplicate"],
+      injected.iter().map(Fault::kind).collect::<Vec<&str>>()
+    );
+  }
+
+  #[test]
+  fn should_count_the_faults_by_kind() {
+    let counters = FaultCounters::default();
+
+    counters.record(&Fault::Dropped);
+    let count = counters.record(&Fault::Dropped);
+    counters.record(&Fault::Truncated(3));
+
+    assert_eq!(2, count);
+    assert_eq!(1, counters.get("truncate"));
+    assert_eq!(0, counters.get("spike"));
+  }
+
+  #[test]
+  fn should_reject_invalid_probabilities() {
+    let faults = FaultsSpec {
+      drop: Some(Chance { probability: 1.5 }),
+      ..FaultsSpec::default()
+    };
+
+    assert!(faults.validate().is_err());
+  }
+}
@@ -1,18 +1,31 @@
 use super::super::util::read_file;
+use super::admin::AdminSpec;
+use super::delay::{self, DelaySpec, VarDelay};
+use super::engine::{self, HandlebarsTemplate};
+use super::expr;
+use super::fake::{self, FakeSpec};
+use super::fault::FaultsSpec;
+use super::json_path::JsonPath;
+use super::load::LoadSpec;
+use super::patch::{self, PatchOp};
+use super::time::{self, DateBase, DateTimeSpec, TimestampSpec};
+use super::toxic::{ToxicCommand, ToxicSpec};
 use chrono::*;
 use failure::{err_msg, Error};
-use jsonpath::Selector;
-use mustache::{compile_str, Data, MapBuilder};
+use mustache::{compile_str, Data};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use serde::de::DeserializeOwned;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use serde_json::{from_value, Value as JsonValue};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env::var;
+use std::fmt;
 use std::io::Cursor;
 use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -26,8 +39,10 @@ pub type RoutingKey = String;
 pub struct Imposter {
   pub connection: Connection,
   pub reactors: Vec<ReactorSpec>,
-  #[serde(skip)] // TODO: add later
+  #[serde(default)]
   pub generators: Vec<GeneratorSpec>,
+  #[serde(default)]
+  pub admin: Option<AdminSpec>,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
@@ -36,6 +51,109 @@ pub struct ReactorSpec {
   pub exchange: ExchangeName,
   pub routing_key: RoutingKey,
   pub action: Vec<ActionSpec>,
+  #[serde(default)]
+  pub mode: ActionMode,
+  /// Faults injected in the messages of all the actions, unless an action defines its own.
+  #[serde(default)]
+  pub faults: Option<FaultsSpec>,
+  #[serde(default)]
+  pub toxic: Option<ToxicSpec>,
+}
+
+/// How the actions of a reactor are run on each incoming message.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum ActionMode {
+  /// Each action is scheduled from the reception of the message.
+  Independent,
+  /// The actions run one after the other: each delay starts once the previous message is published, each action sees
+  /// the variables of the previous ones, and a failure stops the chain.
+  Chain,
+  /// Only one of the actions runs, chosen for each incoming message.
+  Choice(ChoiceSpec),
+}
+
+impl Default for ActionMode {
+  fn default() -> ActionMode {
+    ActionMode::Independent
+  }
+}
+
+impl ActionMode {
+  pub fn validate(&self, actions: usize) -> Result<(), Error> {
+    match self {
+      ActionMode::Choice(choice) => choice.validate(actions),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// How to choose the action to run.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum ChoiceSpec {
+  /// One weight per action: with `[9, 1]`, the first action runs 90% of the time.
+  Weighted(Vec<u32>),
+  /// Any action, with the same probability.
+  Random,
+  /// Each action in turn.
+  RoundRobin,
+  /// The first action for the first n messages, then the second one.
+  First(u64),
+}
+
+impl ChoiceSpec {
+  pub fn validate(&self, actions: usize) -> Result<(), Error> {
+    if actions == 0 {
+      return Err(err_msg("A choice needs at least one action"));
+    }
+    match self {
+      ChoiceSpec::Weighted(weights) if weights.len() != actions => Err(format_err!(
+        "{} weights are given for {} actions",
+        weights.len(),
+        actions
+      )),
+      ChoiceSpec::Weighted(weights) if weights.iter().all(|w| *w == 0) => {
+        Err(err_msg("At least one weight must be positive"))
+      }
+      ChoiceSpec::First(_) if actions != 2 => Err(format_err!(
+        "A choice of the first messages needs 2 actions, not {}",
+        actions
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  /// The index of the action to run for the given incoming message, counted from 0.
+  pub fn pick<R: Rng + ?Sized>(&self, received: u64, actions: usize, rng: &mut R) -> Option<usize> {
+    if actions == 0 {
+      return None;
+    }
+    let index = match self {
+      ChoiceSpec::Weighted(weights) => {
+        let total: u64 = weights.iter().map(|w| u64::from(*w)).sum();
+        if total == 0 {
+          return None;
+        }
+        let mut drawn = rng.gen_range(0, total);
+        weights
+          .iter()
+          .position(|w| {
+            let w = u64::from(*w);
+            if drawn < w {
+              true
+            } else {
+              drawn -= w;
+              false
+            }
+          })
+          .unwrap_or(0)
+      }
+      ChoiceSpec::Random => rng.gen_range(0, actions),
+      ChoiceSpec::RoundRobin => (received % actions as u64) as usize,
+      ChoiceSpec::First(n) if received < *n => 0,
+      ChoiceSpec::First(_) => 1,
+    };
+    Some(index.min(actions - 1))
+  }
 }
 
 pub type HValue = Lit;
@@ -49,14 +167,245 @@ pub struct ActionSpec {
   pub payload: PayloadTemplate,
   pub headers: HeadersSpec,
   pub schedule: ScheduleSpec,
+  #[serde(default)]
+  pub on_error: FailurePolicy,
+  #[serde(default)]
+  pub repeat: Option<RepeatSpec>,
+  #[serde(default)]
+  pub faults: Option<FaultsSpec>,
+  /// Applied to the reactor once the messages of the action are published.
+  #[serde(default)]
+  pub toxic: Option<ToxicCommand>,
+}
+
+impl ActionSpec {
+  /// Checks what can be checked before receiving any message: json paths, header paths, expressions, date formats
+  /// and the paths of the echo operations.
+  pub fn validate(&self) -> Result<(), Error> {
+    for (name, spec) in self.variables.iter() {
+      if let Some(path) = spec.var.json_path() {
+        path
+          .validate()
+          .map_err(|e| format_err!("Variable {}: {}", name, e))?;
+      }
+      match spec.var {
+        Var::Expr(ref e) => expr::parse(e).map(|_| ()),
+        Var::HeaderPath(ref p) => parse_header_path(p).map(|_| ()),
+        Var::CustomDateTime(ref date) => date.validate(),
+        Var::CustomTimestamp(ref timestamp) => timestamp.validate(),
+        _ => Ok(()),
+      }
+      .map_err(|err| format_err!("Variable {}: {}", name, err))?;
+    }
+    evaluation_order(&self.variables)?;
+    self.payload.validate()?;
+    if let FailurePolicy::Publish(ref spec) = self.on_error {
+      spec.payload.validate()?;
+    }
+    if let Some(ref repeat) = self.repeat {
+      repeat.validate()?;
+    }
+    if let Some(ref faults) = self.faults {
+      faults.validate()?;
+    }
+    if let Some(ref toxic) = self.toxic {
+      toxic.validate()?;
+    }
+    self.schedule.validate()
+  }
+
+  /// Checks that the variable the schedule depends on is defined: by the action, by one of the `previous` actions of a
+  /// chain, or as a built-in variable.
+  pub fn validate_schedule(&self, previous: &[ActionSpec]) -> Result<(), Error> {
+    let name = match self.schedule.variable() {
+      Some(name) => name,
+      None => return Ok(()),
+    };
+    let defined = STANDARD_PROPERTIES.contains(&name)
+      || MESSAGE_VARIABLES.contains(&name)
+      || (self.repeat.is_some() && name == "iteration")
+      || iter::once(self)
+        .chain(previous.iter())
+        .any(|action| action.variables.contains_key(name));
+    if defined {
+      Ok(())
+    } else {
+      Err(format_err!("Unknown variable {} in the schedule", name))
+    }
+  }
+
+  /// Builds what does not change from one message to the other, such as the handlebars templates.
+  pub fn compile(&mut self) -> Result<(), Error> {
+    for (name, spec) in self.variables.iter_mut() {
+      if let Some(path) = spec.var.json_path_mut() {
+        path
+          .compile()
+          .map_err(|e| format_err!("Variable {}: {}", name, e))?;
+      }
+    }
+    self.payload.compile()?;
+    match self.on_error {
+      FailurePolicy::Publish(ref mut spec) => spec.payload.compile(),
+      _ => Ok(()),
+    }
+  }
+
+  /// Whether the action expects a json body.
+  pub fn reads_json_body(&self) -> bool {
+    let echo = match self.payload {
+      PayloadTemplate::Echo(ref operations) => !operations.is_empty(),
+      _ => false,
+    };
+    echo
+      || self
+        .variables
+        .values()
+        .any(|spec| spec.var.json_path().is_some())
+  }
+}
+
+/// What to do when a (non optional) variable cannot be computed.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum FailurePolicy {
+  /// Log an error, and publish nothing.
+  Fail,
+  /// Log a warning, and publish nothing.
+  Skip,
+  /// Publish anyway, leaving unset the variables that cannot be computed and have no default.
+  UseDefaults,
+  /// Publish an error message instead, which can use the `error` variable.
+  Publish(ErrorMessageSpec),
+}
+
+impl Default for FailurePolicy {
+  fn default() -> FailurePolicy {
+    FailurePolicy::Fail
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ErrorMessageSpec {
+  /// Defaults to the destination of the action.
+  #[serde(default)]
+  pub to: Option<RouteSpec>,
+  pub payload: PayloadTemplate,
+  #[serde(default)]
+  pub headers: HeadersSpec,
 }
 
+#[derive(Debug, Fail)]
+#[fail(display = "Action skipped: {}", _0)]
+pub struct ActionSkipped(pub String);
+
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(untagged)]
 pub enum Lit {
   Int(i64),
   Str(String),
   Real(f64),
+  Bool(bool),
+  Null,
+  /// Written `{ "Typed": { "LongInt": 1 } }`: without the explicit tag, a json object such as `{ "Timestamp": 1 }`
+  /// would be read as a typed value.
+  #[serde(deserialize_with = "typed_lit")]
+  Typed(TypedLit),
+  Json(JsonValue),
+}
+
+fn typed_lit<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TypedLit, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(deny_unknown_fields)]
+  struct Tagged {
+    #[serde(rename = "Typed")]
+    typed: TypedLit,
+  }
+  Tagged::deserialize(deserializer).map(|tagged| tagged.typed)
+}
+
+/// Values that only exist in AMQP field tables, kept as-is so that incoming headers are not altered.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum TypedLit {
+  ShortShortInt(i8),
+  ShortShortUInt(u8),
+  ShortInt(i16),
+  ShortUInt(u16),
+  LongInt(i32),
+  LongUInt(u32),
+  Float(f32),
+  Decimal { scale: u8, value: u32 },
+  Timestamp(u64),
+  Bytes(Vec<u8>),
+  Table(BTreeMap<String, Lit>),
+  Array(Vec<Lit>),
+}
+
+impl Lit {
+  pub fn as_i64(&self) -> Option<i64> {
+    match self {
+      Lit::Int(i) => Some(*i),
+      Lit::Typed(t) => t.as_i64(),
+      _ => None,
+    }
+  }
+
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      Lit::Real(r) => Some(*r),
+      Lit::Int(i) => Some(*i as f64),
+      Lit::Typed(t) => t.as_f64(),
+      _ => None,
+    }
+  }
+
+  pub fn to_json(&self) -> JsonValue {
+    match self {
+      Lit::Int(i) => JsonValue::from(*i),
+      Lit::Str(s) => JsonValue::from(s.clone()),
+      Lit::Real(r) => JsonValue::from(*r),
+      Lit::Bool(b) => JsonValue::from(*b),
+      Lit::Null => JsonValue::Null,
+      Lit::Typed(t) => t.to_json(),
+      Lit::Json(j) => j.clone(),
+    }
+  }
+}
+
+impl TypedLit {
+  pub fn as_i64(&self) -> Option<i64> {
+    match self {
+      TypedLit::ShortShortInt(i) => Some(*i as i64),
+      TypedLit::ShortShortUInt(i) => Some(*i as i64),
+      TypedLit::ShortInt(i) => Some(*i as i64),
+      TypedLit::ShortUInt(i) => Some(*i as i64),
+      TypedLit::LongInt(i) => Some(*i as i64),
+      TypedLit::LongUInt(i) => Some(*i as i64),
+      TypedLit::Timestamp(t) => Some(*t as i64),
+      _ => None,
+    }
+  }
+
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      TypedLit::Float(f) => Some(*f as f64),
+      TypedLit::Decimal { scale, value } => Some(*value as f64 / 10f64.powi(*scale as i32)),
+      t => t.as_i64().map(|i| i as f64),
+    }
+  }
+
+  pub fn to_json(&self) -> JsonValue {
+    match self {
+      TypedLit::Bytes(b) => JsonValue::from(String::from_utf8_lossy(b).into_owned()),
+      TypedLit::Table(t) => {
+        JsonValue::Object(t.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())
+      }
+      TypedLit::Array(a) => JsonValue::Array(a.iter().map(Lit::to_json).collect()),
+      TypedLit::Float(_) | TypedLit::Decimal { .. } => self
+        .as_f64()
+        .map(JsonValue::from)
+        .unwrap_or(JsonValue::Null),
+      t => t.as_i64().map(JsonValue::from).unwrap_or(JsonValue::Null),
+    }
+  }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
@@ -64,42 +413,140 @@ pub enum VarRef {
   Int(String),
   Str(String),
   Real(String),
+  Bool(String),
+  Json(String),
+  Any(String),
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 pub enum HeaderValueSpec {
   Lit(Lit),
   VarRef(VarRef),
+  Template(HeaderTemplate),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderTemplate {
+  Str(String),
+  Typed {
+    template: String,
+    #[serde(rename = "type")]
+    target: LitType,
+  },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum LitType {
+  Str,
+  Int,
+  Real,
+  Bool,
 }
 pub type HeadersSpec = HashMap<String, HeaderValueSpec>;
 pub type VariablesSpec = HashMap<String, VarSpec>;
 pub type Variables = HashMap<String, Lit>;
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct VarSpec(pub Var);
+pub struct VarSpec {
+  #[serde(flatten)]
+  pub var: Var,
+  #[serde(default)]
+  pub optional: bool,
+  #[serde(default)]
+  pub default: Option<Lit>,
+}
 
 impl VarSpec {
   pub fn new(var: Var) -> VarSpec {
-    VarSpec { 0: var }
+    VarSpec {
+      var,
+      optional: false,
+      default: None,
+    }
+  }
+
+  /// Reads a variable given on the command line: either its json specification, such as `{ "type": "UuidGen" }`, or a
+  /// string.
+  pub fn parse(spec: &str) -> Result<VarSpec, Error> {
+    if spec.trim_start().starts_with('{') {
+      serde_json::from_str(spec).map_err(|e| format_err!("Invalid variable {}: {}", spec, e))
+    } else {
+      Ok(VarSpec::new(Var::Lit(Lit::Str(spec.to_owned()))))
+    }
   }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(tag = "type", content = "param")]
 pub enum Var {
-  StrJsonPath(String),
-  IntJsonPath(String),
+  StrJsonPath(JsonPath),
+  IntJsonPath(JsonPath),
+  RealJsonPath(JsonPath),
+  BoolJsonPath(JsonPath),
+  JsonPath(JsonPath),
+  JsonPathAll(JsonPath),
   UuidGen,
   StrGen(u8),
   IntGen,
   RealGen,
+  BoolGen,
+  FakeGen(FakeSpec),
   Env(String),
   StrHeader(String),
   IntHeader(String),
+  RealHeader(String),
+  BoolHeader(String),
+  HeaderPath(String),
   DateTime,
   Timestamp,
+  CustomDateTime(DateTimeSpec),
+  CustomTimestamp(TimestampSpec),
   Lit(Lit),
+  Expr(String),
+}
+
+impl Var {
+  /// The json path used to extract the variable from the body, if any.
+  pub fn json_path(&self) -> Option<&JsonPath> {
+    match self {
+      Var::StrJsonPath(p)
+      | Var::IntJsonPath(p)
+      | Var::RealJsonPath(p)
+      | Var::BoolJsonPath(p)
+      | Var::JsonPath(p)
+      | Var::JsonPathAll(p) => Some(p),
+      Var::CustomDateTime(DateTimeSpec {
+        from: DateBase::JsonPath(p),
+        ..
+      })
+      | Var::CustomTimestamp(TimestampSpec {
+        from: DateBase::JsonPath(p),
+        ..
+      }) => Some(p),
+      _ => None,
+    }
+  }
+
+  fn json_path_mut(&mut self) -> Option<&mut JsonPath> {
+    match self {
+      Var::StrJsonPath(p)
+      | Var::IntJsonPath(p)
+      | Var::RealJsonPath(p)
+      | Var::BoolJsonPath(p)
+      | Var::JsonPath(p)
+      | Var::JsonPathAll(p) => Some(p),
+      Var::CustomDateTime(DateTimeSpec {
+        from: DateBase::JsonPath(p),
+        ..
+      })
+      | Var::CustomTimestamp(TimestampSpec {
+        from: DateBase::JsonPath(p),
+        ..
+      }) => Some(p),
+      _ => None,
+    }
+  }
 }
 
 pub struct Random<'a, R: Rng> {
@@ -129,12 +576,13 @@ impl<'a, R: Rng> Eval<i64> for Random<'a, R> {
         .headers
         .get(h)
         .ok_or(format_err!("Cannot get header {}", h))
-        .and_then(|l| match l {
-          Lit::Int(i) => Ok(*i),
-          _ => Err(format_err!("Cannot get header {} of type Int", h)),
+        .and_then(|l| {
+          l.as_i64()
+            .ok_or(format_err!("Cannot get header {} of type Int", h))
         }),
-      Var::IntJsonPath(p) => get_value_from_body(input_message, p),
+      Var::IntJsonPath(p) => Body::new(input_message).value(p),
       Var::Timestamp => Ok(current_time()),
+      Var::CustomTimestamp(spec) => eval_timestamp(spec, input_message, &Body::new(input_message)),
       _ => Err(format_err!("Cannot get an int from {:?}", variable)),
     }
   }
@@ -153,26 +601,68 @@ impl<'a, R: Rng> Eval<String> for Random<'a, R> {
           Lit::Str(s) => Ok(s.clone()),
           _ => Err(format_err!("Cannot get header {} of type Str", h)),
         }),
-      Var::StrJsonPath(p) => get_value_from_body(input_message, p),
+      Var::StrJsonPath(p) => Body::new(input_message).value(p),
       Var::DateTime => Ok(now().to_string()),
+      Var::CustomDateTime(spec) => eval_datetime(spec, input_message, &Body::new(input_message)),
       Var::UuidGen => Ok(Uuid::new_v4().to_hyphenated().to_string()),
+      Var::FakeGen(spec) => Ok(fake::generate(&mut *self.rng.borrow_mut(), spec)),
       _ => Err(format_err!("Cannot get a string from {:?}", variable)),
     }
   }
 }
 
 impl<'a, R: Rng> Eval<f64> for Random<'a, R> {
-  fn eval(&self, variable: &Var, _input_message: &Message) -> Result<f64, Error> {
+  fn eval(&self, variable: &Var, input_message: &Message) -> Result<f64, Error> {
     match &variable {
       Var::Env(e) => var(e)
         .map_err(Error::from)
         .and_then(|s| s.parse::<f64>().map_err(Error::from)),
       Var::RealGen => Ok(self.rng.borrow_mut().gen()),
+      Var::RealHeader(h) => input_message
+        .headers
+        .get(h)
+        .ok_or(format_err!("Cannot get header {}", h))
+        .and_then(|l| {
+          l.as_f64()
+            .ok_or(format_err!("Cannot get header {} of type Real", h))
+        }),
+      Var::RealJsonPath(p) => Body::new(input_message).value(p),
       _ => Err(format_err!("Cannot get a real from {:?}", variable)),
     }
   }
 }
 
+impl<'a, R: Rng> Eval<bool> for Random<'a, R> {
+  fn eval(&self, variable: &Var, input_message: &Message) -> Result<bool, Error> {
+    match &variable {
+      Var::Env(e) => var(e)
+        .map_err(Error::from)
+        .and_then(|s| s.parse::<bool>().map_err(Error::from)),
+      Var::BoolGen => Ok(self.rng.borrow_mut().gen()),
+      Var::BoolHeader(h) => input_message
+        .headers
+        .get(h)
+        .ok_or(format_err!("Cannot get header {}", h))
+        .and_then(|l| match l {
+          Lit::Bool(b) => Ok(*b),
+          _ => Err(format_err!("Cannot get header {} of type Bool", h)),
+        }),
+      Var::BoolJsonPath(p) => Body::new(input_message).value(p),
+      _ => Err(format_err!("Cannot get a bool from {:?}", variable)),
+    }
+  }
+}
+
+impl<'a, R: Rng> Eval<JsonValue> for Random<'a, R> {
+  fn eval(&self, variable: &Var, input_message: &Message) -> Result<JsonValue, Error> {
+    match &variable {
+      Var::JsonPath(p) => Body::new(input_message).value(p),
+      Var::JsonPathAll(p) => Body::new(input_message).values(p).map(JsonValue::Array),
+      _ => Err(format_err!("Cannot get a json value from {:?}", variable)),
+    }
+  }
+}
+
 impl<'a, R: Rng> Random<'a, R> {
   fn gen_str(&self, sz: usize) -> String {
     iter::repeat(())
@@ -190,6 +680,48 @@ fn current_time() -> i64 {
   now().timestamp_nanos()
 }
 
+fn eval_datetime(
+  spec: &DateTimeSpec,
+  input_message: &Message,
+  body: &Body,
+) -> Result<String, Error> {
+  eval_date(&spec.from, &spec.offset, input_message, body).and_then(|d| time::format_date(d, spec))
+}
+
+fn eval_timestamp(
+  spec: &TimestampSpec,
+  input_message: &Message,
+  body: &Body,
+) -> Result<i64, Error> {
+  eval_date(&spec.from, &spec.offset, input_message, body)
+    .and_then(|d| time::to_timestamp(d, &spec.unit))
+}
+
+fn eval_date(
+  base: &DateBase,
+  offset: &Option<String>,
+  input_message: &Message,
+  body: &Body,
+) -> Result<DateTime<FixedOffset>, Error> {
+  let date = match base {
+    DateBase::Now => Ok(now().with_timezone(&FixedOffset::east(0))),
+    DateBase::Header(h) => match input_message.headers.get(h) {
+      Some(Lit::Str(s)) => time::parse_date(s),
+      Some(header) => match header.as_i64() {
+        Some(seconds) => time::from_timestamp(seconds),
+        None => Err(format_err!("Cannot get a date from header {}", h)),
+      },
+      None => Err(format_err!("Cannot get header {}", h)),
+    },
+    DateBase::JsonPath(p) => match body.value::<JsonValue>(p)? {
+      JsonValue::String(s) => time::parse_date(&s),
+      JsonValue::Number(ref n) if n.is_i64() => time::from_timestamp(n.as_i64().unwrap()),
+      _ => Err(format_err!("Cannot get a date from path {}", p)),
+    },
+  }?;
+  time::apply_offset(date, offset)
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RouteSpec {
@@ -197,6 +729,8 @@ pub struct RouteSpec {
   pub exchange: Option<ExchangeName>,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub routing_key: Option<RoutingKey>,
+  #[serde(default)]
+  pub reply: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -209,19 +743,162 @@ pub type CronExpr = String;
 
 #[derive(PartialEq, Debug, Deserialize)]
 pub struct GeneratorSpec {
-  pub cron: CronExpr,
+  #[serde(default)]
+  pub cron: Option<CronExpr>,
   pub action: Vec<ActionSpec>,
+  #[serde(default)]
+  pub load: Option<LoadSpec>,
+}
+
+impl GeneratorSpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    if self.action.is_empty() {
+      return Err(err_msg("A generator needs at least one action"));
+    }
+    for action in self.action.iter() {
+      action.validate()?;
+      action.validate_schedule(&[])?;
+      if action.toxic.is_some() {
+        return Err(err_msg("A generator has no reactor to apply a toxic to"));
+      }
+    }
+    match (&self.cron, &self.load) {
+      (None, Some(load)) => load.validate(),
+      (Some(_), None) => Ok(()),
+      (Some(_), Some(_)) => Err(err_msg("A generator needs a cron or a load, not both")),
+      (None, None) => Err(err_msg("A generator needs a cron or a load")),
+    }
+  }
+
+  pub fn compile(&mut self) -> Result<(), Error> {
+    self.action.iter_mut().map(ActionSpec::compile).collect()
+  }
 }
 
 #[derive(PartialEq, Clone, Debug, Deserialize)]
 pub enum PayloadTemplate {
   Inline(String),
   File(String),
+  Handlebars(HandlebarsTemplate),
+  Json(JsonTemplate),
+  /// The incoming json body, transformed by the operations.
+  Echo(Vec<PatchOp>),
+}
+
+impl PayloadTemplate {
+  pub fn validate(&self) -> Result<(), Error> {
+    match self {
+      PayloadTemplate::Echo(operations) => operations.iter().map(PatchOp::validate).collect(),
+      _ => Ok(()),
+    }
+  }
+
+  pub fn compile(&mut self) -> Result<(), Error> {
+    match self {
+      PayloadTemplate::Handlebars(template) => template.compile(),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// A JSON document whose string leaves (and keys) are Handlebars templates. A leaf made of a single placeholder,
+/// such as `"{{ count }}"`, is replaced by the typed value of the variable.
+#[derive(PartialEq, Clone, Debug, Deserialize)]
+pub enum JsonTemplate {
+  Inline(JsonValue),
+  File(String),
 }
 
+/// When to send the message: after `seconds`, after a `delay`, after the delay held by the variable `after`, or at the
+/// date held by the variable `at`. A random `jitter` can be added, and the delay is bounded by `max`.
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 pub struct ScheduleSpec {
+  #[serde(default)]
   pub seconds: u8,
+  #[serde(default)]
+  pub delay: Option<DelaySpec>,
+  #[serde(default)]
+  pub after: Option<VarDelay>,
+  #[serde(default)]
+  pub at: Option<VarDelay>,
+  #[serde(default)]
+  pub jitter: Option<String>,
+  #[serde(default)]
+  pub max: Option<String>,
+}
+
+/// The bound of the delays read from variables, when no `max` is given.
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(3600);
+
+impl ScheduleSpec {
+  pub fn from_secs(seconds: u8) -> ScheduleSpec {
+    ScheduleSpec {
+      seconds,
+      delay: None,
+      after: None,
+      at: None,
+      jitter: None,
+      max: None,
+    }
+  }
+
+  /// The variable the delay depends on, if any.
+  pub fn variable(&self) -> Option<&str> {
+    self
+      .after
+      .as_ref()
+      .or_else(|| self.at.as_ref())
+      .map(|v| v.var.as_ref())
+  }
+
+  pub fn validate(&self) -> Result<(), Error> {
+    let sources = [
+      self.seconds != 0,
+      self.delay.is_some(),
+      self.after.is_some(),
+      self.at.is_some(),
+    ];
+    if sources.iter().filter(|s| **s).count() > 1 {
+      return Err(err_msg(
+        "A schedule can only define one of seconds, delay, after and at",
+      ));
+    }
+    if let Some(ref delay) = self.delay {
+      delay.validate()?;
+    }
+    for duration in self.jitter.iter().chain(self.max.iter()) {
+      delay::validate_duration(duration)?;
+    }
+    Ok(())
+  }
+
+  pub fn delay<R: Rng + ?Sized>(&self, vars: &Variables, rng: &mut R) -> Result<Duration, Error> {
+    let base = match (&self.delay, &self.after, &self.at) {
+      (Some(delay), _, _) => delay.sample(rng)?,
+      (_, Some(after), _) => delay::delay_from_var(after, vars)?,
+      (_, _, Some(at)) => delay::delay_until_var(at, vars)?,
+      _ => Duration::from_secs(self.seconds.into()),
+    };
+    let jittered = match self.jitter {
+      Some(ref j) => delay::jitter(base, j, rng)?,
+      None => base,
+    };
+    let max = match self.max {
+      Some(ref m) => Some(delay::to_std(m)?),
+      None if self.variable().is_some() => Some(DEFAULT_MAX_DELAY),
+      None => None,
+    };
+    match max {
+      Some(max) if jittered > max => {
+        warn!(
+          "The delay {:?} exceeds the maximum, {:?} is used",
+          jittered, max
+        );
+        Ok(max)
+      }
+      _ => Ok(jittered),
+    }
+  }
 }
 
 impl From<ScheduleSpec> for Schedule {
@@ -233,45 +910,309 @@ impl From<ScheduleSpec> for Schedule {
   }
 }
 
+/// Publishes the message of an action again and again. The `iteration` variable holds the index of the message, from 0.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct RepeatSpec {
+  /// The number of messages.
+  #[serde(default)]
+  pub count: Option<u32>,
+  /// The duration between two messages.
+  pub interval: String,
+  /// A condition, evaluated on the variables of each message: once true, no more message is published.
+  #[serde(default)]
+  pub until: Option<String>,
+}
+
+impl RepeatSpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    if self.count.is_none() && self.until.is_none() {
+      return Err(err_msg("A repetition needs a count or an until condition"));
+    }
+    if self.count == Some(0) {
+      return Err(err_msg("A repetition needs a count of at least 1"));
+    }
+    if let Some(ref until) = self.until {
+      expr::parse(until).map_err(|e| format_err!("Invalid until condition: {}", e))?;
+    }
+    delay::validate_duration(&self.interval)?;
+    if self.count.is_none() && self.interval()? == Duration::from_secs(0) {
+      return Err(err_msg(
+        "A repetition without a count needs a non-zero interval",
+      ));
+    }
+    Ok(())
+  }
+
+  pub fn interval(&self) -> Result<Duration, Error> {
+    delay::to_std(&self.interval)
+  }
+
+  /// Whether the message of the given iteration, computed with these variables, is the last one.
+  pub fn is_over(&self, iteration: u32, vars: &Variables) -> Result<bool, Error> {
+    if self.count.map_or(false, |count| iteration + 1 >= count) {
+      return Ok(true);
+    }
+    match self.until {
+      Some(ref until) => expr::parse(until)?
+        .eval_bool(vars)
+        .map_err(|e| format_err!("Cannot evaluate the until condition: {}", e)),
+      None => Ok(false),
+    }
+  }
+}
+
+/// The variables visible to an iteration of a repeated action.
+pub fn iteration_variables(previous: &Variables, iteration: u32) -> Variables {
+  let mut variables = previous.clone();
+  variables.insert("iteration".to_owned(), Lit::Int(iteration.into()));
+  variables
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Message {
   pub payload: Vec<u8>,
   pub headers: Headers,
   pub route: Route,
+  pub delivery: Option<DeliveryInfo>,
+  pub json_body: JsonBody,
 }
 
-impl Message {
-  fn get_reply_to(&self) -> Option<String> {
-    self
-      .headers
-      .get(&"reply_to".to_owned())
-      .and_then(|v| match v {
-        Lit::Str(x) => Some(x.clone()),
-        _ => None, // should never happen
-      })
+/// What the broker tells about a consumed message, besides its route and properties.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliveryInfo {
+  pub delivery_tag: u64,
+  pub redelivered: bool,
+  pub queue: QueueName,
+}
+
+/// The payload of a message parsed as json, when first needed. The clones of a message share it, so that its payload is
+/// parsed once, however many actions read it.
+#[derive(Clone, Default)]
+pub struct JsonBody(Arc<Mutex<Option<Result<Arc<JsonValue>, String>>>>);
+
+impl JsonBody {
+  pub fn parse(&self, payload: &[u8]) -> Result<Arc<JsonValue>, Error> {
+    let mut parsed = self
+      .0
+      .lock()
+      .map_err(|_| err_msg("The parsed body is poisoned"))?;
+    let parsed = parsed.get_or_insert_with(|| {
+      serde_json::from_slice(payload)
+        .map(Arc::new)
+        .map_err(|e| e.to_string())
+    });
+    match parsed {
+      Ok(json) => Ok(json.clone()),
+      Err(e) => Err(format_err!("The incoming body is not valid json: {}", e)),
+    }
+  }
+}
+
+/// A cache: whether the payload was parsed yet does not tell messages apart.
+impl PartialEq for JsonBody {
+  fn eq(&self, _other: &JsonBody) -> bool {
+    true
   }
 }
 
+impl fmt::Debug for JsonBody {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("JsonBody")
+  }
+}
+
+const STANDARD_PROPERTIES: [&str; 13] = [
+  "content_type",
+  "content_encoding",
+  "delivery_mode",
+  "priority",
+  "correlation_id",
+  "reply_to",
+  "expiration",
+  "message_id",
+  "timestamp",
+  "type",
+  "user_id",
+  "app_id",
+  "cluster_id",
+];
+
 pub fn handle_message<E>(
   action: &ActionSpec,
   input_message: &Message,
   evaluator: &E,
 ) -> Result<Message, Error>
 where
-  E: Eval<i64> + Eval<String> + Eval<f64>,
+  E: Eval<i64> + Eval<String> + Eval<f64> + Eval<bool> + Eval<JsonValue>,
+{
+  handle_chained_message(action, input_message, &Variables::new(), evaluator).map(|(msg, _)| msg)
+}
+
+/// Handles a message within a chain: the variables of the previous actions are visible to this one, and its own
+/// variables are returned along with the message, for the next ones.
+pub fn handle_chained_message<E>(
+  action: &ActionSpec,
+  input_message: &Message,
+  previous: &Variables,
+  evaluator: &E,
+) -> Result<(Message, Variables), Error>
+where
+  E: Eval<i64> + Eval<String> + Eval<f64> + Eval<bool> + Eval<JsonValue>,
+{
+  handle_scheduled_message(
+    action,
+    input_message,
+    previous,
+    &Variables::new(),
+    evaluator,
+  )
+}
+
+/// Handles a message whose `scheduled` variables were computed along with its delay (see `schedule_delay`): they keep
+/// their values, and only the other variables are computed.
+pub fn handle_scheduled_message<E>(
+  action: &ActionSpec,
+  input_message: &Message,
+  previous: &Variables,
+  scheduled: &Variables,
+  evaluator: &E,
+) -> Result<(Message, Variables), Error>
+where
+  E: Eval<i64> + Eval<String> + Eval<f64> + Eval<bool> + Eval<JsonValue>,
 {
   debug!("Computing variables");
-  let variables = eval_var_spec(&action.variables, input_message, evaluator)?;
+  let lenient = action.on_error == FailurePolicy::UseDefaults;
+  let pending: VariablesSpec = action
+    .variables
+    .iter()
+    .filter(|(name, _)| !scheduled.contains_key(*name))
+    .map(|(name, spec)| (name.clone(), spec.clone()))
+    .collect();
+  let mut known = previous.clone();
+  known.extend(scheduled.clone());
+  let variables = match eval_var_spec(&pending, input_message, &known, evaluator, lenient) {
+    Ok(variables) => variables,
+    Err(e) => return handle_failure(action, input_message, previous, e),
+  };
+  match build_message(
+    &action.to,
+    &action.payload,
+    &action.headers,
+    &variables,
+    input_message,
+  ) {
+    Ok(msg) => Ok((msg, variables)),
+    Err(e) => handle_failure(action, input_message, previous, e),
+  }
+}
+
+/// Handles a failure to compute the variables, the delay or the message of the action, according to its `on_error` policy. The
+/// `error` variable is only visible to the error message: it is not returned with the variables.
+pub fn handle_failure(
+  action: &ActionSpec,
+  input_message: &Message,
+  previous: &Variables,
+  error: Error,
+) -> Result<(Message, Variables), Error> {
+  match action.on_error {
+    FailurePolicy::Skip => Err(Error::from(ActionSkipped(error.to_string()))),
+    FailurePolicy::Publish(ref spec) => {
+      warn!("Publishing an error message: {}", error);
+      let mut variables = builtin_variables(input_message);
+      variables.extend(previous.clone());
+      variables.insert("error".to_owned(), Lit::Str(error.to_string()));
+      let to = spec.to.as_ref().unwrap_or(&action.to);
+      build_message(to, &spec.payload, &spec.headers, &variables, input_message).map(|msg| {
+        variables.remove("error");
+        (msg, variables)
+      })
+    }
+    _ => Err(error),
+  }
+}
+
+/// Computes the delay before sending the message of the action. When the delay depends on a variable, this variable
+/// (along with the variables it references) is evaluated on reception of the incoming message, and is returned, to be
+/// given to `handle_scheduled_message`.
+pub fn schedule_delay<E, R>(
+  action: &ActionSpec,
+  input_message: &Message,
+  previous: &Variables,
+  evaluator: &E,
+  rng: &mut R,
+) -> Result<(Duration, Variables), Error>
+where
+  E: Eval<i64> + Eval<String> + Eval<f64> + Eval<bool> + Eval<JsonValue>,
+  R: Rng + ?Sized,
+{
+  let scheduled = match action.schedule.variable() {
+    Some(name) => {
+      let required = required_variables(&action.variables, name);
+      let lenient = action.on_error == FailurePolicy::UseDefaults;
+      let mut variables = eval_var_spec(&required, input_message, previous, evaluator, lenient)?;
+      variables.retain(|name, _| required.contains_key(name));
+      variables
+    }
+    None => Variables::new(),
+  };
+  let mut variables = builtin_variables(input_message);
+  variables.extend(previous.clone());
+  variables.extend(scheduled.clone());
+  action
+    .schedule
+    .delay(&variables, rng)
+    .map(|delay| (delay, scheduled))
+}
+
+/// The specification of a variable, and of the variables it references, recursively.
+fn required_variables(var_specs: &VariablesSpec, name: &str) -> VariablesSpec {
+  let mut required = VariablesSpec::new();
+  let mut pending = vec![name.to_owned()];
+  while let Some(name) = pending.pop() {
+    if required.contains_key(&name) {
+      continue;
+    }
+    if let Some(spec) = var_specs.get(&name) {
+      if let Var::Expr(ref e) = spec.var {
+        if let Ok(parsed) = expr::parse(e) {
+          pending.extend(parsed.variables());
+        }
+      }
+      required.insert(name, spec.clone());
+    }
+  }
+  required
+}
+
+fn build_message(
+  to: &RouteSpec,
+  payload: &PayloadTemplate,
+  headers: &HeadersSpec,
+  variables: &Variables,
+  input_message: &Message,
+) -> Result<Message, Error> {
   trace!("Filling the payload template...");
-  let payload = action.payload.fill(&variables)?;
+  let payload = match payload {
+    PayloadTemplate::Echo(ref operations) => echo(input_message, operations, variables)?,
+    template => template.fill(variables)?,
+  };
   trace!("Filling the headers template...");
-  let headers = action.headers.fill(&variables)?;
+  let mut headers = headers.fill(variables)?;
+  if to.reply {
+    if let Some(correlation_id) = variables.get("correlation_id") {
+      headers
+        .entry("correlation_id".to_owned())
+        .or_insert_with(|| correlation_id.clone());
+    }
+  }
   trace!("Filling the route template...");
-  let route = action.to.fill(&variables)?;
+  let route = to.fill(variables)?;
   Ok(Message {
     headers,
     payload: payload.into_bytes(),
     route,
+    delivery: None,
+    json_body: JsonBody::default(),
   })
 }
 
@@ -283,46 +1224,48 @@ fn get_reply_to(vars: &Variables) -> Result<String, Error> {
   match vars.get("reply_to") {
     Some(Lit::Str(r)) => Ok(r.clone()),
     Some(_) => Err(err_msg("reply_to has not the good type")),
-    None => Err(err_msg("Variable not found: reply_to")),
+    None => Err(err_msg(
+      "No routing key is configured, and the incoming message has no reply_to property to reply to",
+    )),
   }
 }
 
 impl Template<Route> for RouteSpec {
   fn fill(&self, vars: &Variables) -> Result<Route, Error> {
-    match (&self.exchange, &self.routing_key) {
-      (None, _) => {
-        let reply_to = get_reply_to(vars)?;
-        Ok(Route {
-          exchange: "".to_owned(),
-          routing_key: reply_to,
-        })
-      }
-      (Some(ref e), _) if e.is_empty() => {
-        let reply_to = get_reply_to(vars)?;
-        Ok(Route {
-          exchange: e.clone(),
-          routing_key: reply_to,
-        })
-      }
-      (Some(ref e), None) => {
-        let reply_to = get_reply_to(vars)?;
-        Ok(Route {
-          exchange: e.clone(),
-          routing_key: reply_to,
-        })
-      }
-      (Some(ref e), Some(ref r)) if r.is_empty() => {
-        let reply_to = get_reply_to(vars)?;
-        Ok(Route {
-          exchange: e.clone(),
-          routing_key: reply_to,
-        })
-      }
-      (Some(ref e), Some(ref r)) => Ok(Route {
-        exchange: e.clone(),
-        routing_key: r.clone(),
-      }),
+    if self.reply {
+      return match (&self.exchange, &self.routing_key) {
+        (None, None) => match vars.get("reply_to") {
+          // direct reply-to (amq.rabbitmq.reply-to) also goes through the default exchange
+          Some(Lit::Str(r)) => Ok(Route {
+            exchange: "".to_owned(),
+            routing_key: r.clone(),
+          }),
+          _ => Err(err_msg(
+            "Cannot reply: the incoming message has no reply_to property",
+          )),
+        },
+        _ => Err(err_msg(
+          "A reply route cannot define an exchange or a routing key",
+        )),
+      };
     }
+    let exchange = match self.exchange {
+      Some(ref e) => Some(render_text_template(e, vars)?),
+      None => None,
+    };
+    let routing_key = match self.routing_key {
+      Some(ref r) => Some(render_text_template(r, vars)?),
+      None => None,
+    };
+    // Without routing key, the message goes to the reply_to queue, through the default exchange unless another is set.
+    let routing_key = match routing_key.filter(|r| !r.is_empty()) {
+      Some(routing_key) => routing_key,
+      None => get_reply_to(vars)?,
+    };
+    Ok(Route {
+      exchange: exchange.unwrap_or_default(),
+      routing_key,
+    })
   }
 }
 
@@ -343,21 +1286,114 @@ fn eval_var_ref(var_ref: &VarRef, vars: &Variables) -> Result<HValue, Error> {
       Some(_) => Err(format_err!("Type mismatch for variable reference {}", &r)),
       None => Err(format_err!("Variable not found {}", &r)),
     },
+    VarRef::Bool(ref r) => match vars.get(r) {
+      Some(Lit::Bool(b)) => Ok(Lit::Bool(*b)),
+      Some(_) => Err(format_err!("Type mismatch for variable reference {}", &r)),
+      None => Err(format_err!("Variable not found {}", &r)),
+    },
+    VarRef::Json(ref r) => match vars.get(r) {
+      Some(Lit::Json(j)) => Ok(Lit::Json(j.clone())),
+      Some(_) => Err(format_err!("Type mismatch for variable reference {}", &r)),
+      None => Err(format_err!("Variable not found {}", &r)),
+    },
+    VarRef::Any(ref r) => vars
+      .get(r)
+      .cloned()
+      .ok_or(format_err!("Variable not found {}", &r)),
   }
 }
 
-fn get_value_from_body<T>(msg: &Message, json_path: &str) -> Result<T, Error>
-where
-  T: DeserializeOwned,
-{
-  let json: JsonValue = serde_json::from_slice(&msg.payload).unwrap();
-  let selector = Selector::new(json_path).unwrap();
+enum PathSegment {
+  Key(String),
+  Index(usize),
+}
 
-  let data: Vec<&JsonValue> = selector.find(&json).collect();
-  if data.len() == 1 {
-    from_value::<T>(data[0].clone()).map_err(Error::from)
-  } else {
-    Err(format_err!("Cannot get value from path {}", json_path))
+fn parse_header_path(path: &str) -> Result<Vec<PathSegment>, Error> {
+  let invalid = || format_err!("Invalid header path {}", path);
+  let mut segments = Vec::new();
+  let mut rest = path;
+  while !rest.is_empty() {
+    if rest.starts_with('[') {
+      let end = rest.find(']').ok_or_else(invalid)?;
+      let inner = &rest[1..end];
+      let quoted = inner.len() >= 2
+        && (inner.starts_with('\'') && inner.ends_with('\'')
+          || inner.starts_with('"') && inner.ends_with('"'));
+      if quoted {
+        segments.push(PathSegment::Key(inner[1..inner.len() - 1].to_owned()));
+      } else {
+        segments.push(PathSegment::Index(
+          inner.parse::<usize>().map_err(|_| invalid())?,
+        ));
+      }
+      rest = &rest[end + 1..];
+    } else {
+      if !segments.is_empty() {
+        if !rest.starts_with('.') {
+          return Err(invalid());
+        }
+        rest = &rest[1..];
+      }
+      let end = rest.find(|c| c == '.' || c == '[').unwrap_or(rest.len());
+      if end == 0 {
+        return Err(invalid());
+      }
+      segments.push(PathSegment::Key(rest[..end].to_owned()));
+      rest = &rest[end..];
+    }
+  }
+  Ok(segments)
+}
+
+/// Reads a value nested in the headers, e.g. `x-death[0].count` or `['header.with.dots'].key`.
+fn get_value_from_headers(headers: &Headers, path: &str) -> Result<Lit, Error> {
+  let segments = parse_header_path(path)?;
+  let (first, rest) = match segments.split_first() {
+    Some((PathSegment::Key(name), rest)) => (name, rest),
+    _ => return Err(format_err!("Invalid header path {}", path)),
+  };
+  let header = headers
+    .get(first)
+    .ok_or(format_err!("Cannot get header {}", first))?;
+  rest
+    .iter()
+    .fold(Some(header), |value, segment| match (value, segment) {
+      (Some(Lit::Typed(TypedLit::Table(t))), PathSegment::Key(k)) => t.get(k),
+      (Some(Lit::Typed(TypedLit::Array(a))), PathSegment::Index(i)) => a.get(*i),
+      _ => None,
+    })
+    .cloned()
+    .ok_or(format_err!("Cannot get header value at path {}", path))
+}
+
+/// The body of the incoming message, parsed as json by its `JsonBody`.
+struct Body<'a> {
+  msg: &'a Message,
+}
+
+impl<'a> Body<'a> {
+  fn new(msg: &'a Message) -> Body<'a> {
+    Body { msg }
+  }
+
+  fn json(&self) -> Result<Arc<JsonValue>, Error> {
+    self.msg.json_body.parse(&self.msg.payload)
+  }
+
+  fn values(&self, json_path: &JsonPath) -> Result<Vec<JsonValue>, Error> {
+    json_path.find(&*self.json()?)
+  }
+
+  fn value<T>(&self, json_path: &JsonPath) -> Result<T, Error>
+  where
+    T: DeserializeOwned,
+  {
+    let mut data = self.values(json_path)?;
+    if data.len() == 1 {
+      from_value::<T>(data.remove(0)).map_err(Error::from)
+    } else {
+      Err(format_err!("Cannot get value from path {}", json_path))
+    }
   }
 }
 
@@ -365,6 +1401,38 @@ fn eval_header_spec(spec: &HeaderValueSpec, vars: &Variables) -> Result<HValue,
   match spec {
     HeaderValueSpec::Lit(l) => Ok(l.clone()),
     HeaderValueSpec::VarRef(var_ref) => eval_var_ref(var_ref, vars),
+    HeaderValueSpec::Template(HeaderTemplate::Str(t)) => {
+      render_text_template(t, vars).map(Lit::Str)
+    }
+    HeaderValueSpec::Template(HeaderTemplate::Typed { template, target }) => {
+      let rendered = render_text_template(template, vars)?;
+      let parsed = match target {
+        LitType::Str => Ok(Lit::Str(rendered.clone())),
+        LitType::Int => rendered
+          .trim()
+          .parse::<i64>()
+          .map(Lit::Int)
+          .map_err(Error::from),
+        LitType::Real => rendered
+          .trim()
+          .parse::<f64>()
+          .map(Lit::Real)
+          .map_err(Error::from),
+        LitType::Bool => rendered
+          .trim()
+          .parse::<bool>()
+          .map(Lit::Bool)
+          .map_err(Error::from),
+      };
+      parsed.map_err(|e| {
+        format_err!(
+          "Cannot read header value {} as {:?}: {}",
+          rendered,
+          target,
+          e
+        )
+      })
+    }
   }
 }
 
@@ -374,24 +1442,195 @@ impl Template<Headers> for HeadersSpec {
       .iter()
       .fold(Ok(Headers::new()), |acc: Result<Headers, Error>, (k, v)| {
         let h = &mut acc?;
+        let name = render_text_template(k, vars)?;
         let value = eval_header_spec(v, vars)?;
-        h.insert(k.clone(), value);
+        h.insert(name, value);
         Ok(h.clone())
       })
   }
 }
 
+/// Renders a template which is not HTML, such as a route or a header: the values are not escaped, as if each `{{ name }}` was
+/// written `{{{ name }}}`.
+fn render_text_template(template: &str, vars: &Variables) -> Result<String, Error> {
+  render_template(&unescaped_tags(template), vars)
+}
+
+/// Turns the escaped tags of a mustache template into unescaped ones: `{{ name }}` becomes `{{& name }}`.
+fn unescaped_tags(template: &str) -> String {
+  let mut result = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    let (before, tag) = rest.split_at(start + 2);
+    result.push_str(before);
+    if !tag.starts_with(|c| "{&#^/!>=".contains(c)) {
+      result.push('&');
+    }
+    rest = tag;
+  }
+  result.push_str(rest);
+  result
+}
+
+fn render_template(template: &str, vars: &Variables) -> Result<String, Error> {
+  let data = to_hash_map(vars);
+  let template = compile_str(&json_tags(template, vars))?;
+  let mut out = Cursor::new(Vec::new());
+  // mustache panics when a tag renders a list, a map or a boolean, e.g. a value nested in a json variable.
+  panic::catch_unwind(AssertUnwindSafe(|| template.render_data(&mut out, &data))).map_err(
+    |_| {
+      err_msg("A tag renders a list, an object or a boolean: use a section, or the _json variable")
+    },
+  )??;
+  String::from_utf8(out.into_inner()).map_err(Error::from)
+}
+
+/// Points the tags of the variables rendered as json at their `_json` twin, e.g. `{{ items }}` becomes
+/// `{{ items_json }}`, so that the variable itself can be used in sections.
+fn json_tags(template: &str, vars: &Variables) -> String {
+  let mut result = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    let (before, tag) = rest.split_at(start + 2);
+    result.push_str(before);
+    let sigil = if tag.starts_with(|c| c == '{' || c == '&') {
+      1
+    } else {
+      0
+    };
+    let name_start = sigil + tag[sigil..].len() - tag[sigil..].trim_start().len();
+    let name_len = tag[name_start..]
+      .find(|c: char| c.is_whitespace() || c == '}')
+      .unwrap_or_else(|| tag.len() - name_start);
+    let name = &tag[name_start..name_start + name_len];
+    result.push_str(&tag[..name_start + name_len]);
+    if vars.get(name).map_or(false, renders_as_json) {
+      result.push_str("_json");
+    }
+    rest = &tag[name_start + name_len..];
+  }
+  result.push_str(rest);
+  result
+}
+
+/// Whether the variable is rendered by mustache as a list, a map or a boolean, rather than as text.
+fn renders_as_json(value: &Lit) -> bool {
+  match value {
+    Lit::Bool(_) | Lit::Null | Lit::Json(_) => true,
+    Lit::Typed(TypedLit::Table(_)) | Lit::Typed(TypedLit::Array(_)) => true,
+    _ => false,
+  }
+}
+
 impl Template<String> for PayloadTemplate {
   fn fill(&self, vars: &Variables) -> Result<String, Error> {
-    let data = to_hash_map(vars)?;
     let payload = match self {
       PayloadTemplate::Inline(s) => Ok(s.clone()),
       PayloadTemplate::File(p) => read_file(p),
+      PayloadTemplate::Handlebars(t) => return engine::render(t, &template_data(vars)),
+      PayloadTemplate::Json(t) => {
+        let document = match t {
+          JsonTemplate::Inline(j) => j.clone(),
+          JsonTemplate::File(p) => serde_json::from_str(&read_file(p)?)
+            .map_err(|e| format_err!("Invalid json template {}: {}", p, e))?,
+        };
+        return Ok(fill_json(&document, vars, &template_data(vars))?.to_string());
+      }
+      PayloadTemplate::Echo(_) => Err(err_msg(
+        "An echo payload can only be filled from an incoming message",
+      )),
     }?;
-    let template = compile_str(&payload)?;
-    let mut out = Cursor::new(Vec::new());
-    template.render_data(&mut out, &data)?;
-    String::from_utf8(out.into_inner()).map_err(Error::from)
+    render_template(&payload, vars)
+  }
+}
+
+fn template_data(vars: &Variables) -> JsonValue {
+  JsonValue::Object(vars.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())
+}
+
+fn echo(
+  input_message: &Message,
+  operations: &[PatchOp],
+  vars: &Variables,
+) -> Result<String, Error> {
+  if operations.is_empty() {
+    return Ok(String::from_utf8_lossy(&input_message.payload).into_owned());
+  }
+  let mut body: JsonValue = (*input_message
+    .json_body
+    .parse(&input_message.payload)
+    .map_err(|e| format_err!("Cannot patch the incoming body: {}", e))?)
+  .clone();
+  let data = template_data(vars);
+  for operation in operations {
+    patch::apply(
+      &mut body,
+      operation.map_values(|v| fill_json(v, vars, &data))?,
+    )?;
+  }
+  Ok(body.to_string())
+}
+
+/// Returns the variable name when the template is a lone placeholder, e.g. `{{ count }}`.
+fn placeholder(template: &str) -> Option<&str> {
+  let trimmed = template.trim();
+  if !trimmed.starts_with("{{") || !trimmed.ends_with("}}") || trimmed.len() < 4 {
+    return None;
+  }
+  let name = trimmed[2..trimmed.len() - 2].trim();
+  let is_name = !name.is_empty()
+    && name
+      .chars()
+      .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.');
+  if is_name {
+    Some(name)
+  } else {
+    None
+  }
+}
+
+/// Reads the value of a placeholder: a variable, or a path in a variable as in handlebars, e.g. `order.lines.0.sku`.
+fn resolve_placeholder(name: &str, vars: &Variables) -> Result<JsonValue, Error> {
+  if let Some(value) = vars.get(name) {
+    return Ok(value.to_json());
+  }
+  let mut segments = name.split('.');
+  let variable = segments.next().unwrap_or(name);
+  let mut value = vars
+    .get(variable)
+    .map(Lit::to_json)
+    .ok_or_else(|| format_err!("Unknown variable {} in json template", variable))?;
+  for segment in segments {
+    let next = match value {
+      JsonValue::Object(ref mut fields) => fields.remove(segment),
+      JsonValue::Array(ref mut items) => match segment.parse::<usize>() {
+        Ok(i) if i < items.len() => Some(items.swap_remove(i)),
+        _ => None,
+      },
+      _ => None,
+    };
+    value = next.ok_or_else(|| format_err!("Unknown path {} in json template", name))?;
+  }
+  Ok(value)
+}
+
+fn fill_json(template: &JsonValue, vars: &Variables, data: &JsonValue) -> Result<JsonValue, Error> {
+  match template {
+    JsonValue::String(s) => match placeholder(s) {
+      Some(name) => resolve_placeholder(name, vars),
+      None => engine::render_raw(s, data).map(JsonValue::String),
+    },
+    JsonValue::Array(items) => items
+      .iter()
+      .map(|item| fill_json(item, vars, data))
+      .collect::<Result<Vec<JsonValue>, Error>>()
+      .map(JsonValue::Array),
+    JsonValue::Object(fields) => fields
+      .iter()
+      .map(|(k, v)| Ok((engine::render_raw(k, data)?, fill_json(v, vars, data)?)))
+      .collect::<Result<serde_json::Map<String, JsonValue>, Error>>()
+      .map(JsonValue::Object),
+    j => Ok(j.clone()),
   }
 }
 
@@ -401,107 +1640,222 @@ impl From<Lit> for String {
       Lit::Str(s) => s.clone(),
       Lit::Int(i) => i.to_string(),
       Lit::Real(r) => r.to_string(),
+      Lit::Bool(b) => b.to_string(),
+      Lit::Null => "null".to_owned(),
+      Lit::Typed(t) => match t.to_json() {
+        JsonValue::String(s) => s,
+        j => j.to_string(),
+      },
+      Lit::Json(j) => j.to_string(),
+    }
+  }
+}
+
+/// The variables describing the incoming message, besides its standard properties.
+const MESSAGE_VARIABLES: [&str; 6] = [
+  "exchange",
+  "routing_key",
+  "body",
+  "delivery_tag",
+  "redelivered",
+  "queue",
+];
+
+/// Variables available to every action, describing the incoming message.
+fn builtin_variables(input_message: &Message) -> Variables {
+  let mut variables = Variables::new();
+  for property in STANDARD_PROPERTIES.iter() {
+    if let Some(value) = input_message.headers.get(*property) {
+      variables.insert(property.to_string(), value.clone());
     }
   }
+  variables.insert(
+    "exchange".to_owned(),
+    Lit::Str(input_message.route.exchange.clone()),
+  );
+  variables.insert(
+    "routing_key".to_owned(),
+    Lit::Str(input_message.route.routing_key.clone()),
+  );
+  variables.insert(
+    "body".to_owned(),
+    Lit::Str(String::from_utf8_lossy(&input_message.payload).into_owned()),
+  );
+  if let Some(ref delivery) = input_message.delivery {
+    variables.insert(
+      "delivery_tag".to_owned(),
+      Lit::Int(delivery.delivery_tag as i64),
+    );
+    variables.insert("redelivered".to_owned(), Lit::Bool(delivery.redelivered));
+    variables.insert("queue".to_owned(), Lit::Str(delivery.queue.clone()));
+  }
+  variables
+}
+
+/// Orders the variables by name, except that an expression comes after the variables it references.
+fn evaluation_order(var_specs: &VariablesSpec) -> Result<Vec<(&String, &VarSpec)>, Error> {
+  fn visit<'a>(
+    name: &'a String,
+    var_specs: &'a VariablesSpec,
+    visiting: &mut Vec<&'a String>,
+    order: &mut Vec<(&'a String, &'a VarSpec)>,
+  ) -> Result<(), Error> {
+    if order.iter().any(|(n, _)| *n == name) {
+      return Ok(());
+    }
+    if visiting.contains(&name) {
+      return Err(format_err!(
+        "Circular reference through the variable {}",
+        name
+      ));
+    }
+    let spec = &var_specs[name];
+    if let Var::Expr(ref e) = spec.var {
+      visiting.push(name);
+      let mut dependencies = expr::parse(e)?.variables();
+      dependencies.sort();
+      for dependency in dependencies {
+        if let Some(key) = var_specs.keys().find(|k| **k == dependency) {
+          visit(key, var_specs, visiting, order)?;
+        }
+      }
+      visiting.pop();
+    }
+    order.push((name, spec));
+    Ok(())
+  }
+
+  let mut names: Vec<&String> = var_specs.keys().collect();
+  names.sort();
+  let mut order = Vec::new();
+  for name in names {
+    visit(name, var_specs, &mut Vec::new(), &mut order)?;
+  }
+  Ok(order)
 }
 
+/// Computes a variable. The values read from the body are read from `body`, parsed once for all the variables.
+fn eval_var<E>(
+  name: &str,
+  var: &Var,
+  vars: &Variables,
+  input_message: &Message,
+  body: &Body,
+  evaluator: &E,
+) -> Result<Lit, Error>
+where
+  E: Eval<i64> + Eval<String> + Eval<f64> + Eval<bool> + Eval<JsonValue>,
+{
+  match var {
+    Var::Lit(v) => Ok(v.clone()),
+    Var::Expr(e) => expr::parse(e)?
+      .eval(vars)
+      .map_err(|err| format_err!("Cannot evaluate {}: {}", name, err)),
+    Var::HeaderPath(p) => get_value_from_headers(&input_message.headers, p),
+    Var::StrJsonPath(p) => body.value(p).map(Lit::Str),
+    Var::IntJsonPath(p) => body.value(p).map(Lit::Int),
+    Var::RealJsonPath(p) => body.value(p).map(Lit::Real),
+    Var::BoolJsonPath(p) => body.value(p).map(Lit::Bool),
+    Var::JsonPath(p) => body.value(p).map(Lit::Json),
+    Var::JsonPathAll(p) => body.values(p).map(|v| Lit::Json(JsonValue::Array(v))),
+    Var::CustomDateTime(spec) => eval_datetime(spec, input_message, body).map(Lit::Str),
+    Var::CustomTimestamp(spec) => eval_timestamp(spec, input_message, body).map(Lit::Int),
+    Var::StrHeader(_)
+    | Var::StrGen(_)
+    | Var::Env(_)
+    | Var::FakeGen(_)
+    | Var::UuidGen
+    | Var::DateTime => evaluator.eval(var, input_message).map(Lit::Str),
+    Var::IntGen | Var::IntHeader(_) | Var::Timestamp => {
+      evaluator.eval(var, input_message).map(Lit::Int)
+    }
+    Var::RealGen | Var::RealHeader(_) => evaluator.eval(var, input_message).map(Lit::Real),
+    Var::BoolGen | Var::BoolHeader(_) => evaluator.eval(var, input_message).map(Lit::Bool),
+  }
+}
+
+/// Computes the variables. A variable that cannot be computed takes its default value if any; otherwise it is left
+/// unset when it is optional (or when `lenient` is set), and fails the whole computation when it is not.
 fn eval_var_spec<E>(
   var_specs: &VariablesSpec,
   input_message: &Message,
+  previous: &Variables,
   evaluator: &E,
+  lenient: bool,
 ) -> Result<Variables, Error>
 where
-  E: Eval<i64> + Eval<String> + Eval<f64>,
+  E: Eval<i64> + Eval<String> + Eval<f64> + Eval<bool> + Eval<JsonValue>,
 {
-  let variables = match input_message.get_reply_to() {
-    Some(r) => {
-      let mut variables = Variables::new();
-      variables.insert("reply_to".to_owned(), Lit::Str(r));
-      variables
-    }
-    None => Variables::new(),
-  };
+  let mut variables = builtin_variables(input_message);
+  variables.extend(previous.clone());
+  let order = evaluation_order(var_specs)?;
+  let body = Body::new(input_message);
 
-  var_specs.iter().fold(Ok(variables), |acc, (k, var_spec)| {
+  order.into_iter().fold(Ok(variables), |acc, (k, var_spec)| {
     let mut vars = acc?;
-    let spec = var_spec.0.clone();
-    match spec {
-      Var::Lit(v) => {
-        vars.insert(k.clone(), v.clone());
-        Ok(vars)
-      }
-      x @ Var::StrHeader(_) => {
-        let s_val: String = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Str(s_val));
-        Ok(vars)
-      }
-      x @ Var::StrJsonPath(_) => {
-        let s_val: String = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Str(s_val));
-        Ok(vars)
-      }
-      x @ Var::StrGen(_) => {
-        let s_val: String = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Str(s_val));
-        Ok(vars)
-      }
-      x @ Var::Env(_) => {
-        let s_val: String = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Str(s_val));
-        Ok(vars)
-      }
-      x @ Var::UuidGen => {
-        let s_val: String = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Str(s_val));
-        Ok(vars)
-      }
-      x @ Var::DateTime => {
-        let s_val: String = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Str(s_val));
-        Ok(vars)
+    match eval_var(k, &var_spec.var, &vars, input_message, &body, evaluator) {
+      Ok(value) => {
+        vars.insert(k.clone(), value);
       }
-      x @ Var::IntGen => {
-        let i_val: i64 = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Int(i_val));
-        Ok(vars)
-      }
-      x @ Var::IntHeader(_) => {
-        let i_val: i64 = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Int(i_val));
-        Ok(vars)
+      Err(e) => match var_spec.default {
+        Some(ref default) => {
+          debug!("Using the default value of {}: {}", k, e);
+          vars.insert(k.clone(), default.clone());
+        }
+        None if var_spec.optional || lenient => debug!("Leaving {} unset: {}", k, e),
+        None => return Err(format_err!("Cannot compute the variable {}: {}", k, e)),
+      },
+    }
+    Ok(vars)
+  })
+}
+
+fn to_hash_map(vars: &Variables) -> Data {
+  let mut data = HashMap::new();
+  for (k, v) in vars.iter() {
+    let value = match v {
+      Lit::Int(i) => Data::String(i.to_string()),
+      Lit::Str(s) => Data::String(s.clone()),
+      Lit::Real(r) => Data::String(r.to_string()),
+      Lit::Bool(b) => {
+        data.insert(format!("{}_json", k), Data::String(b.to_string()));
+        Data::Bool(*b)
       }
-      x @ Var::IntJsonPath(_) => {
-        let i_val: i64 = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Int(i_val));
-        Ok(vars)
+      Lit::Null => {
+        data.insert(format!("{}_json", k), Data::String("null".to_owned()));
+        Data::Null
       }
-      x @ Var::Timestamp => {
-        let i_val: i64 = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Int(i_val));
-        Ok(vars)
+      Lit::Typed(t) => {
+        let j = t.to_json();
+        data.insert(format!("{}_json", k), Data::String(j.to_string()));
+        to_data(&j)
       }
-      x @ Var::RealGen => {
-        let r_val: f64 = evaluator.eval(&x, input_message)?;
-        vars.insert(k.clone(), Lit::Real(r_val));
-        Ok(vars)
+      Lit::Json(j) => {
+        data.insert(format!("{}_json", k), Data::String(j.to_string()));
+        to_data(j)
       }
-    }
-  })
+    };
+    data.insert(k.clone(), value);
+  }
+  Data::Map(data)
 }
 
-fn to_hash_map(vars: &Variables) -> Result<Data, Error> {
-  vars
-    .iter()
-    .fold(Ok(MapBuilder::new()), |acc, (k, v)| {
-      let map = acc?;
-      match v {
-        Lit::Int(i) => map.insert(k.clone(), &i),
-        Lit::Str(s) => map.insert(k.clone(), &s.clone()),
-        Lit::Real(r) => map.insert(k.clone(), &r),
-      }
-    })
-    .map(MapBuilder::build)
-    .map_err(Error::from)
+/// The json value as mustache data: the numbers are rendered as text, and the other values are kept as is, so that they
+/// can be used in sections.
+fn to_data(json: &JsonValue) -> Data {
+  match json {
+    JsonValue::Null => Data::Null,
+    JsonValue::Bool(b) => Data::Bool(*b),
+    JsonValue::Number(n) => Data::String(n.to_string()),
+    JsonValue::String(s) => Data::String(s.clone()),
+    JsonValue::Array(values) => Data::Vec(values.iter().map(to_data).collect()),
+    JsonValue::Object(fields) => Data::Map(
+      fields
+        .iter()
+        .map(|(k, v)| (k.clone(), to_data(v)))
+        .collect(),
+    ),
+  }
 }
 
 // **************************
@@ -516,13 +1870,1047 @@ enum Schedule {
 mod tests {
 
   use super::*;
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
 
   #[test]
   fn schedule() {
-    let spec = ScheduleSpec { seconds: 5 };
+    let spec = ScheduleSpec::from_secs(5);
 
     let sched: Schedule = spec.into();
 
     assert_eq!(sched, Schedule::Delay(Duration::from_secs(5)));
   }
+
+  fn message(payload: &str, headers: Headers) -> Message {
+    Message {
+      payload: payload.as_bytes().to_vec(),
+      headers,
+      route: Route {
+        exchange: "x".to_owned(),
+        routing_key: "r.k".to_owned(),
+      },
+      delivery: None,
+      json_body: JsonBody::default(),
+    }
+  }
+
+  #[test]
+  fn should_extract_reals_from_headers_and_body() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(
+      r#"{ "order": { "price": 12.5 } }"#,
+      hashmap! { "quantity".to_owned() => Lit::Real(0.75), "count".to_owned() => Lit::Int(3) },
+    );
+
+    let price: f64 = evaluator
+      .eval(&Var::RealJsonPath(JsonPath::from("$.order.price")), &input)
+      .unwrap();
+    let quantity: f64 = evaluator
+      .eval(&Var::RealHeader("quantity".to_owned()), &input)
+      .unwrap();
+    let count: f64 = evaluator
+      .eval(&Var::RealHeader("count".to_owned()), &input)
+      .unwrap();
+
+    assert_eq!((12.5, 0.75, 3.0), (price, quantity, count));
+  }
+
+  #[test]
+  fn should_iterate_and_render_json_variables() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(
+      r#"{ "items": [ { "sku": "a", "qty": 1 }, { "sku": "b", "qty": 2 } ] }"#,
+      Headers::new(),
+    );
+    let action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {
+        "items".to_owned() => VarSpec::new(Var::JsonPath(JsonPath::from("$.items"))),
+        "skus".to_owned() => VarSpec::new(Var::JsonPathAll(JsonPath::from("$.items.*.sku"))),
+      },
+      payload: PayloadTemplate::Inline(
+        "{{#items}}{{sku}}x{{qty}} {{/items}}{{{skus_json}}}".to_owned(),
+      ),
+      headers: HeadersSpec::new(),
+      schedule: ScheduleSpec::from_secs(0),
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let output = handle_message(&action, &input, &evaluator).unwrap();
+
+    assert_eq!(
+      "ax1 bx2 [\"a\",\"b\"]",
+      String::from_utf8(output.payload).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_render_json_variables_as_text_without_panicking() {
+    let vars = hashmap! {
+      "order".to_owned() => Lit::Json(json!({ "id": 1, "items": [{ "sku": "a", "gift": true }] })),
+    };
+
+    assert_eq!(
+      r#"{"id":1,"items":[{"gift":true,"sku":"a"}]}"#,
+      render_template("{{{ order }}}", &vars).unwrap()
+    );
+    assert_eq!(
+      "{&quot;id&quot;:1,&quot;items&quot;:[{&quot;gift&quot;:true,&quot;sku&quot;:&quot;a&quot;}]}",
+      render_template("{{order}}", &vars).unwrap()
+    );
+    assert_eq!(
+      "1: a",
+      render_template(
+        "{{#order}}{{id}}: {{#items}}{{sku}}{{/items}}{{/order}}",
+        &vars
+      )
+      .unwrap()
+    );
+    assert!(render_template("{{#order}}{{#items}}{{gift}}{{/items}}{{/order}}", &vars).is_err());
+  }
+
+  #[test]
+  fn should_render_bool_and_null_literals() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(r#"{ "active": false }"#, Headers::new());
+    let action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {
+        "active".to_owned() => VarSpec::new(Var::BoolJsonPath(JsonPath::from("$.active"))),
+        "nothing".to_owned() => VarSpec::new(Var::Lit(Lit::Null)),
+      },
+      payload: PayloadTemplate::Inline(
+        r#"{"active": {{active}}, "nothing": {{nothing}}}"#.to_owned(),
+      ),
+      headers: hashmap! {
+        "x-active".to_owned() => HeaderValueSpec::VarRef(VarRef::Bool("active".to_owned())),
+      },
+      schedule: ScheduleSpec::from_secs(0),
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let output = handle_message(&action, &input, &evaluator).unwrap();
+
+    assert_eq!(
+      r#"{"active": false, "nothing": null}"#,
+      String::from_utf8(output.payload).unwrap()
+    );
+    assert_eq!(Some(&Lit::Bool(false)), output.headers.get("x-active"));
+  }
+
+  #[test]
+  fn should_use_bool_and_null_variables_in_sections() {
+    let vars = hashmap! {
+      "yes".to_owned() => Lit::Bool(true),
+      "no".to_owned() => Lit::Bool(false),
+      "nothing".to_owned() => Lit::Null,
+    };
+
+    assert_eq!(
+      "yes not-no not-nothing",
+      render_template(
+        "{{#yes}}yes{{/yes}}{{^yes}}not-yes{{/yes}} {{#no}}no{{/no}}{{^no}}not-no{{/no}} \
+         {{#nothing}}nothing{{/nothing}}{{^nothing}}not-nothing{{/nothing}}",
+        &vars
+      )
+      .unwrap()
+    );
+    assert_eq!(
+      "true false null",
+      render_template("{{yes}} {{& no}} {{{nothing}}}", &vars).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_read_dates_from_typed_headers() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(
+      "",
+      hashmap! {
+        "sent_at".to_owned() => Lit::Typed(TypedLit::Timestamp(1_551_483_000)),
+        "expires_at".to_owned() => Lit::Typed(TypedLit::LongInt(1_551_486_600)),
+      },
+    );
+    let spec = |header: &str| TimestampSpec {
+      unit: time::TimeUnit::Seconds,
+      from: DateBase::Header(header.to_owned()),
+      offset: None,
+    };
+
+    let sent_at: i64 = evaluator
+      .eval(&Var::CustomTimestamp(spec("sent_at")), &input)
+      .unwrap();
+    let expires_at: i64 = evaluator
+      .eval(&Var::CustomTimestamp(spec("expires_at")), &input)
+      .unwrap();
+
+    assert_eq!((1_551_483_000, 1_551_486_600), (sent_at, expires_at));
+  }
+
+  #[test]
+  fn should_read_nested_header_values() {
+    let headers = hashmap! {
+      "x-death".to_owned() => Lit::Typed(TypedLit::Array(vec![Lit::Typed(TypedLit::Table(btreemap! {
+        "count".to_owned() => Lit::Int(3),
+        "reason".to_owned() => Lit::Str("expired".to_owned()),
+      }))])),
+      "a.b".to_owned() => Lit::Typed(TypedLit::Table(btreemap! {
+        "c".to_owned() => Lit::Typed(TypedLit::LongInt(7)),
+      })),
+    };
+
+    assert_eq!(
+      Lit::Int(3),
+      get_value_from_headers(&headers, "x-death[0].count").unwrap()
+    );
+    assert_eq!(
+      Lit::Typed(TypedLit::LongInt(7)),
+      get_value_from_headers(&headers, "['a.b'].c").unwrap()
+    );
+    assert!(get_value_from_headers(&headers, "x-death[1].count").is_err());
+  }
+
+  #[test]
+  fn should_render_field_tables_and_arrays_without_panicking() {
+    let vars = hashmap! {
+      "death".to_owned() => Lit::Typed(TypedLit::Array(vec![Lit::Typed(TypedLit::Table(btreemap! {
+        "count".to_owned() => Lit::Typed(TypedLit::LongInt(3)),
+        "reason".to_owned() => Lit::Str("expired".to_owned()),
+      }))])),
+      "priority".to_owned() => Lit::Typed(TypedLit::ShortShortUInt(5)),
+    };
+
+    assert_eq!(
+      r#"[{"count":3,"reason":"expired"}] 5"#,
+      render_template("{{{death}}} {{priority}}", &vars).unwrap()
+    );
+    assert_eq!(
+      "expired x3",
+      render_template("{{#death}}{{reason}} x{{count}}{{/death}}", &vars).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_expose_the_incoming_delivery_as_builtin_variables() {
+    let mut input = message(
+      "hello",
+      hashmap! {
+        "correlation_id".to_owned() => Lit::Str("c-1".to_owned()),
+        "x-custom".to_owned() => Lit::Str("not a property".to_owned()),
+      },
+    );
+    input.delivery = Some(DeliveryInfo {
+      delivery_tag: 12,
+      redelivered: true,
+      queue: "q".to_owned(),
+    });
+
+    let variables = builtin_variables(&input);
+
+    assert_eq!(
+      hashmap! {
+        "correlation_id".to_owned() => Lit::Str("c-1".to_owned()),
+        "exchange".to_owned() => Lit::Str("x".to_owned()),
+        "routing_key".to_owned() => Lit::Str("r.k".to_owned()),
+        "body".to_owned() => Lit::Str("hello".to_owned()),
+        "delivery_tag".to_owned() => Lit::Int(12),
+        "redelivered".to_owned() => Lit::Bool(true),
+        "queue".to_owned() => Lit::Str("q".to_owned()),
+      },
+      variables
+    );
+  }
+
+  #[test]
+  fn should_reply_with_the_incoming_correlation_id() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(
+      "",
+      hashmap! {
+        "reply_to".to_owned() => Lit::Str("amq.rabbitmq.reply-to.g2dkAA".to_owned()),
+        "correlation_id".to_owned() => Lit::Str("c-1".to_owned()),
+      },
+    );
+    let action = ActionSpec {
+      to: RouteSpec {
+        exchange: None,
+        routing_key: None,
+        reply: true,
+      },
+      variables: VariablesSpec::new(),
+      payload: PayloadTemplate::Inline("pong".to_owned()),
+      headers: HeadersSpec::new(),
+      schedule: ScheduleSpec::from_secs(0),
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let output = handle_message(&action, &input, &evaluator).unwrap();
+
+    assert_eq!(
+      Route {
+        exchange: "".to_owned(),
+        routing_key: "amq.rabbitmq.reply-to.g2dkAA".to_owned(),
+      },
+      output.route
+    );
+    assert_eq!(
+      Some(&Lit::Str("c-1".to_owned())),
+      output.headers.get("correlation_id")
+    );
+  }
+
+  #[test]
+  fn should_fail_to_reply_without_reply_to() {
+    let route = RouteSpec {
+      exchange: None,
+      routing_key: None,
+      reply: true,
+    };
+
+    let error = route.fill(&Variables::new()).unwrap_err();
+
+    assert_eq!(
+      "Cannot reply: the incoming message has no reply_to property",
+      error.to_string()
+    );
+  }
+
+  #[test]
+  fn should_render_the_route_from_variables() {
+    let route = RouteSpec {
+      exchange: Some("tenant.{{tenant}}".to_owned()),
+      routing_key: Some("{{tenant}}.events.{{kind}}".to_owned()),
+      reply: false,
+    };
+    let vars = hashmap! {
+      "tenant".to_owned() => Lit::Str("acme".to_owned()),
+      "kind".to_owned() => Lit::Int(3),
+    };
+
+    assert_eq!(
+      Route {
+        exchange: "tenant.acme".to_owned(),
+        routing_key: "acme.events.3".to_owned(),
+      },
+      route.fill(&vars).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_not_escape_the_route() {
+    let route = RouteSpec {
+      exchange: Some("{{{exchange}}}".to_owned()),
+      routing_key: Some("{{ kind }}.{{#tags}}{{.}}{{/tags}}".to_owned()),
+      reply: false,
+    };
+    let vars = hashmap! {
+      "exchange".to_owned() => Lit::Str("a&b".to_owned()),
+      "kind".to_owned() => Lit::Str("<order>".to_owned()),
+      "tags".to_owned() => Lit::Json(json!(["'x'", "\"y\""])),
+    };
+
+    assert_eq!(
+      Route {
+        exchange: "a&b".to_owned(),
+        routing_key: "<order>.'x'\"y\"".to_owned(),
+      },
+      route.fill(&vars).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_route_through_the_default_exchange() {
+    let to_queue = |exchange: Option<&str>| RouteSpec {
+      exchange: exchange.map(str::to_owned),
+      routing_key: Some("queue".to_owned()),
+      reply: false,
+    };
+    let expected = Route {
+      exchange: "".to_owned(),
+      routing_key: "queue".to_owned(),
+    };
+
+    assert_eq!(
+      expected,
+      to_queue(Some("")).fill(&Variables::new()).unwrap()
+    );
+    assert_eq!(expected, to_queue(None).fill(&Variables::new()).unwrap());
+    let to_reply_to = RouteSpec {
+      exchange: Some("".to_owned()),
+      routing_key: None,
+      reply: false,
+    };
+    let vars = hashmap! { "reply_to".to_owned() => Lit::Str("client-q".to_owned()) };
+    assert_eq!(
+      Route {
+        exchange: "".to_owned(),
+        routing_key: "client-q".to_owned(),
+      },
+      to_reply_to.fill(&vars).unwrap()
+    );
+    assert!(to_reply_to.fill(&Variables::new()).is_err());
+  }
+
+  #[test]
+  fn should_render_header_templates() {
+    let headers: HeadersSpec = hashmap! {
+      "authorization".to_owned() => HeaderValueSpec::Template(HeaderTemplate::Str("Bearer {{token}}".to_owned())),
+      "x-{{kind}}-query".to_owned() => HeaderValueSpec::Template(HeaderTemplate::Str("{{query}}".to_owned())),
+      "x-{{kind}}-count".to_owned() => HeaderValueSpec::Template(HeaderTemplate::Typed {
+        template: "{{count}}".to_owned(),
+        target: LitType::Int,
+      }),
+    };
+    let vars = hashmap! {
+      "token".to_owned() => Lit::Str("abc".to_owned()),
+      "kind".to_owned() => Lit::Str("order".to_owned()),
+      "count".to_owned() => Lit::Int(4),
+      "query".to_owned() => Lit::Str("a=1&b=<2>".to_owned()),
+    };
+
+    assert_eq!(
+      hashmap! {
+        "authorization".to_owned() => Lit::Str("Bearer abc".to_owned()),
+        "x-order-query".to_owned() => Lit::Str("a=1&b=<2>".to_owned()),
+        "x-order-count".to_owned() => Lit::Int(4),
+      },
+      headers.fill(&vars).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_fill_json_templates_with_typed_values() {
+    let payload = PayloadTemplate::Json(JsonTemplate::Inline(json!({
+      "comment": "said \"{{ quote }}\"",
+      "count": "{{ count }}",
+      "active": "{{active}}",
+      "items": ["{{ items }}", 1],
+      "{{ kind }}_id": "{{ id }}"
+    })));
+    let vars = hashmap! {
+      "quote".to_owned() => Lit::Str("a \"quoted\"\nline".to_owned()),
+      "count".to_owned() => Lit::Int(4),
+      "active".to_owned() => Lit::Bool(true),
+      "items".to_owned() => Lit::Json(json!([{ "sku": "A1" }])),
+      "kind".to_owned() => Lit::Str("order".to_owned()),
+      "id".to_owned() => Lit::Str("42".to_owned()),
+    };
+
+    let filled: JsonValue = serde_json::from_str(&payload.fill(&vars).unwrap()).unwrap();
+
+    assert_eq!(
+      json!({
+        "comment": "said \"a \"quoted\"\nline\"",
+        "count": 4,
+        "active": true,
+        "items": [[{ "sku": "A1" }], 1],
+        "order_id": "42"
+      }),
+      filled
+    );
+  }
+
+  #[test]
+  fn should_fill_json_templates_with_paths_in_variables() {
+    let payload = PayloadTemplate::Json(JsonTemplate::Inline(json!({
+      "sku": "{{ order.lines.1.sku }}",
+      "line": "{{order.lines.0}}",
+      "dotted": "{{ a.b }}"
+    })));
+    let mut vars = hashmap! {
+      "order".to_owned() => Lit::Json(json!({ "lines": [{ "sku": "A1" }, { "sku": "B2", "qty": 2 }] })),
+      "a.b".to_owned() => Lit::Int(1),
+    };
+
+    let filled: JsonValue = serde_json::from_str(&payload.fill(&vars).unwrap()).unwrap();
+
+    assert_eq!(
+      json!({ "sku": "B2", "line": { "sku": "A1" }, "dotted": 1 }),
+      filled
+    );
+    vars.insert("a".to_owned(), Lit::Str("ab".to_owned()));
+    vars.remove("a.b");
+    assert!(payload.fill(&vars).is_err());
+    let missing = PayloadTemplate::Json(JsonTemplate::Inline(
+      json!({ "x": "{{ order.lines.2.sku }}" }),
+    ));
+    assert!(missing.fill(&vars).is_err());
+  }
+
+  #[test]
+  fn should_evaluate_expressions_after_their_dependencies() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(r#"{ "qty": 12, "sku": "abc-123" }"#, Headers::new());
+    let specs = hashmap! {
+      "a_label".to_owned() => VarSpec::new(Var::Expr("z_kind + '-' + substr(sku, 0, 3)".to_owned())),
+      "qty".to_owned() => VarSpec::new(Var::IntJsonPath(JsonPath::from("$.qty"))),
+      "sku".to_owned() => VarSpec::new(Var::StrJsonPath(JsonPath::from("$.sku"))),
+      "z_kind".to_owned() => VarSpec::new(Var::Expr("qty > 10 ? 'bulk' : 'unit'".to_owned())),
+    };
+
+    let vars = eval_var_spec(&specs, &input, &Variables::new(), &evaluator, false).unwrap();
+
+    assert_eq!(Some(&Lit::Str("bulk-abc".to_owned())), vars.get("a_label"));
+  }
+
+  #[test]
+  fn should_fall_back_on_defaults_for_optional_variables() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(r#"{ "id": 7 }"#, Headers::new());
+    let specs = hashmap! {
+      "id".to_owned() => VarSpec::new(Var::IntJsonPath(JsonPath::from("$.id"))),
+      "name".to_owned() => VarSpec {
+        var: Var::StrJsonPath(JsonPath::from("$.name")),
+        optional: false,
+        default: Some(Lit::Str("anonymous".to_owned())),
+      },
+      "tenant".to_owned() => VarSpec {
+        var: Var::StrHeader("x-tenant".to_owned()),
+        optional: true,
+        default: None,
+      },
+    };
+
+    let vars = eval_var_spec(&specs, &input, &Variables::new(), &evaluator, false).unwrap();
+
+    assert_eq!(Some(&Lit::Int(7)), vars.get("id"));
+    assert_eq!(Some(&Lit::Str("anonymous".to_owned())), vars.get("name"));
+    assert_eq!(None, vars.get("tenant"));
+  }
+
+  #[test]
+  fn should_apply_the_failure_policy() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(r#"{ "id": 7 }"#, Headers::new());
+    let mut action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {
+        "name".to_owned() => VarSpec::new(Var::StrJsonPath(JsonPath::from("$.name"))),
+      },
+      payload: PayloadTemplate::Inline("Hello {{name}}".to_owned()),
+      headers: hashmap! {},
+      schedule: ScheduleSpec::from_secs(0),
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    assert!(handle_message(&action, &input, &evaluator).is_err());
+
+    action.on_error = FailurePolicy::Skip;
+    let skipped = handle_message(&action, &input, &evaluator).unwrap_err();
+    assert!(skipped.downcast_ref::<ActionSkipped>().is_some());
+
+    action.on_error = FailurePolicy::UseDefaults;
+    let output = handle_message(&action, &input, &evaluator).unwrap();
+    assert_eq!("Hello ", String::from_utf8(output.payload).unwrap());
+
+    action.on_error = FailurePolicy::Publish(ErrorMessageSpec {
+      to: Some(RouteSpec {
+        exchange: Some("errors".to_owned()),
+        routing_key: None,
+        reply: false,
+      }),
+      payload: PayloadTemplate::Json(JsonTemplate::Inline(json!({ "error": "{{ error }}" }))),
+      headers: hashmap! {},
+    });
+    let mut headers = Headers::new();
+    headers.insert("reply_to".to_owned(), Lit::Str("client-q".to_owned()));
+    let output = handle_message(&action, &message(r#"{ "id": 7 }"#, headers), &evaluator).unwrap();
+    let payload: JsonValue = serde_json::from_slice(&output.payload).unwrap();
+    assert_eq!(
+      Route {
+        exchange: "errors".to_owned(),
+        routing_key: "client-q".to_owned(),
+      },
+      output.route
+    );
+    assert!(payload["error"].as_str().unwrap().contains("name"));
+  }
+
+  #[test]
+  fn should_fail_without_panicking_on_a_malformed_body() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message("not json", Headers::new());
+    let specs = hashmap! {
+      "id".to_owned() => VarSpec::new(Var::IntJsonPath(JsonPath::from("$.id"))),
+    };
+
+    assert!(eval_var_spec(&specs, &input, &Variables::new(), &evaluator, false).is_err());
+  }
+
+  #[test]
+  fn should_compute_the_delay_from_the_incoming_message() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let mut headers = Headers::new();
+    headers.insert("x-delay".to_owned(), Lit::Int(2));
+    let input = message("{}", headers);
+    let mut schedule = ScheduleSpec::from_secs(0);
+    schedule.after = Some(VarDelay {
+      var: "delay".to_owned(),
+      unit: time::TimeUnit::Millis,
+    });
+    schedule.max = Some("1s".to_owned());
+    let action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {
+        "delay".to_owned() => VarSpec::new(Var::Expr("seconds * 1000 + 500".to_owned())),
+        "seconds".to_owned() => VarSpec::new(Var::IntHeader("x-delay".to_owned())),
+        "unrelated".to_owned() => VarSpec::new(Var::StrJsonPath(JsonPath::from("$.missing"))),
+      },
+      payload: PayloadTemplate::Inline("".to_owned()),
+      headers: hashmap! {},
+      schedule,
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let (delay, scheduled) = schedule_delay(
+      &action,
+      &input,
+      &Variables::new(),
+      &evaluator,
+      &mut rand::thread_rng(),
+    )
+    .unwrap();
+
+    assert_eq!(Duration::from_secs(1), delay);
+    assert_eq!(
+      hashmap! {
+        "delay".to_owned() => Lit::Int(2500),
+        "seconds".to_owned() => Lit::Int(2),
+      },
+      scheduled
+    );
+  }
+
+  #[test]
+  fn should_keep_the_values_computed_for_the_schedule() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message("", Headers::new());
+    let action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {
+        "wait".to_owned() => VarSpec::new(Var::RealGen),
+        "id".to_owned() => VarSpec::new(Var::UuidGen),
+      },
+      payload: PayloadTemplate::Inline("{{wait}}".to_owned()),
+      headers: hashmap! {},
+      schedule: ScheduleSpec {
+        after: Some(VarDelay {
+          var: "wait".to_owned(),
+          unit: time::TimeUnit::Millis,
+        }),
+        ..ScheduleSpec::from_secs(0)
+      },
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let (_, scheduled) = schedule_delay(
+      &action,
+      &input,
+      &Variables::new(),
+      &evaluator,
+      &mut rand::thread_rng(),
+    )
+    .unwrap();
+    let (output, variables) =
+      handle_scheduled_message(&action, &input, &Variables::new(), &scheduled, &evaluator).unwrap();
+
+    assert_eq!(1, scheduled.len());
+    assert_eq!(scheduled.get("wait"), variables.get("wait"));
+    assert!(variables.contains_key("id"));
+    assert_eq!(
+      String::from(scheduled["wait"].clone()),
+      String::from_utf8(output.payload).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_check_the_variable_of_the_schedule() {
+    let action = |name: &str, variables: VariablesSpec| ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables,
+      payload: PayloadTemplate::Inline("".to_owned()),
+      headers: hashmap! {},
+      schedule: ScheduleSpec {
+        at: Some(VarDelay {
+          var: name.to_owned(),
+          unit: time::TimeUnit::Millis,
+        }),
+        ..ScheduleSpec::from_secs(0)
+      },
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+    let first = action(
+      "timestamp",
+      hashmap! { "due".to_owned() => VarSpec::new(Var::Timestamp) },
+    );
+
+    assert!(first.validate_schedule(&[]).is_ok());
+    assert!(action("due", hashmap! {}).validate_schedule(&[]).is_err());
+    assert!(action("due", hashmap! {})
+      .validate_schedule(&[first.clone()])
+      .is_ok());
+  }
+
+  #[test]
+  fn should_handle_a_schedule_failure_according_to_the_policy() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message("", Headers::new());
+    let mut action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {
+        "wait".to_owned() => VarSpec::new(Var::Lit(Lit::Str("soon".to_owned()))),
+      },
+      payload: PayloadTemplate::Inline("".to_owned()),
+      headers: hashmap! {},
+      schedule: ScheduleSpec {
+        after: Some(VarDelay {
+          var: "wait".to_owned(),
+          unit: time::TimeUnit::Millis,
+        }),
+        ..ScheduleSpec::from_secs(0)
+      },
+      on_error: FailurePolicy::Skip,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let error = schedule_delay(
+      &action,
+      &input,
+      &Variables::new(),
+      &evaluator,
+      &mut rand::thread_rng(),
+    )
+    .unwrap_err();
+    let skipped = handle_failure(&action, &input, &Variables::new(), error).unwrap_err();
+    action.on_error = FailurePolicy::Publish(ErrorMessageSpec {
+      to: None,
+      payload: PayloadTemplate::Inline("failed: {{error}}".to_owned()),
+      headers: hashmap! {},
+    });
+    let (published, variables) =
+      handle_failure(&action, &input, &Variables::new(), err_msg("no delay")).unwrap();
+
+    assert!(skipped.downcast_ref::<ActionSkipped>().is_some());
+    assert!(!variables.contains_key("error"));
+    assert_eq!(
+      "failed: no delay",
+      String::from_utf8(published.payload).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_pass_the_variables_along_a_chain() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(r#"{ "id": 7 }"#, Headers::new());
+    let action = |variables: VariablesSpec, payload: &str| ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables,
+      payload: PayloadTemplate::Inline(payload.to_owned()),
+      headers: hashmap! {},
+      schedule: ScheduleSpec::from_secs(0),
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+    let first = action(
+      hashmap! {
+        "order".to_owned() => VarSpec::new(Var::IntJsonPath(JsonPath::from("$.id"))),
+      },
+      "created {{order}}",
+    );
+    let second = action(
+      hashmap! {
+        "next".to_owned() => VarSpec::new(Var::Expr("order + 1".to_owned())),
+      },
+      "shipped {{order}} before {{next}}",
+    );
+
+    let (_, previous) =
+      handle_chained_message(&first, &input, &Variables::new(), &evaluator).unwrap();
+    let (output, _) = handle_chained_message(&second, &input, &previous, &evaluator).unwrap();
+
+    assert_eq!(
+      "shipped 7 before 8",
+      String::from_utf8(output.payload).unwrap()
+    );
+    assert!(handle_message(&second, &input, &evaluator).is_err());
+  }
+
+  #[test]
+  fn should_stop_repeating_after_the_count_or_the_condition() {
+    let repeat = RepeatSpec {
+      count: Some(3),
+      interval: "5s".to_owned(),
+      until: Some("progress >= 100".to_owned()),
+    };
+    let vars = |iteration: u32| {
+      let mut vars = iteration_variables(&Variables::new(), iteration);
+      vars.insert("progress".to_owned(), Lit::Int(i64::from(iteration) * 50));
+      vars
+    };
+
+    assert_eq!(Duration::from_secs(5), repeat.interval().unwrap());
+    assert_eq!(Some(&Lit::Int(1)), vars(1).get("iteration"));
+    assert!(!repeat.is_over(0, &vars(0)).unwrap());
+    assert!(repeat.is_over(2, &vars(2)).unwrap());
+    assert!(RepeatSpec {
+      count: Some(10),
+      ..repeat.clone()
+    }
+    .is_over(2, &vars(2))
+    .unwrap());
+    assert!(RepeatSpec {
+      count: None,
+      until: None,
+      ..repeat.clone()
+    }
+    .validate()
+    .is_err());
+  }
+
+  #[test]
+  fn should_reject_endless_or_empty_repetitions() {
+    let repeat = RepeatSpec {
+      count: Some(3),
+      interval: "0s".to_owned(),
+      until: Some("progress >= 100".to_owned()),
+    };
+
+    assert!(repeat.validate().is_ok());
+    assert!(RepeatSpec {
+      count: Some(0),
+      ..repeat.clone()
+    }
+    .validate()
+    .is_err());
+    assert!(RepeatSpec {
+      count: None,
+      ..repeat.clone()
+    }
+    .validate()
+    .is_err());
+    assert!(RepeatSpec {
+      count: None,
+      interval: "1s".to_owned(),
+      ..repeat
+    }
+    .validate()
+    .is_ok());
+  }
+
+  #[test]
+  fn should_choose_the_action_to_run() {
+    let mut rng = StdRng::seed_from_u64(42);
+    let weighted = ChoiceSpec::Weighted(vec![9, 1, 0]);
+    let picks: Vec<usize> = (0..1000)
+      .map(|n| weighted.pick(n, 3, &mut rng).unwrap())
+      .collect();
+    let first = picks.iter().filter(|i| **i == 0).count();
+
+    assert!(first > 850 && first < 950);
+    assert!(!picks.contains(&2));
+    assert_eq!(
+      vec![0, 1, 2, 0],
+      (0..4)
+        .map(|n| ChoiceSpec::RoundRobin.pick(n, 3, &mut rng).unwrap())
+        .collect::<Vec<usize>>()
+    );
+    assert_eq!(
+      vec![0, 0, 1, 1],
+      (0..4)
+        .map(|n| ChoiceSpec::First(2).pick(n, 2, &mut rng).unwrap())
+        .collect::<Vec<usize>>()
+    );
+    assert_eq!(None, ChoiceSpec::Random.pick(0, 0, &mut rng));
+    assert!(ChoiceSpec::Weighted(vec![1]).validate(2).is_err());
+  }
+
+  #[test]
+  fn should_parse_variables_given_on_the_command_line() {
+    assert_eq!(
+      VarSpec::new(Var::UuidGen),
+      VarSpec::parse(r#"{ "type": "UuidGen" }"#).unwrap()
+    );
+    assert_eq!(
+      VarSpec::new(Var::Lit(Lit::Str("bob".to_owned()))),
+      VarSpec::parse("bob").unwrap()
+    );
+    assert!(VarSpec::parse(r#"{ "type": "Unknown" }"#).is_err());
+  }
+
+  #[test]
+  fn should_reject_circular_expressions() {
+    let specs = hashmap! {
+      "a".to_owned() => VarSpec::new(Var::Expr("b + 1".to_owned())),
+      "b".to_owned() => VarSpec::new(Var::Expr("a + 1".to_owned())),
+    };
+
+    assert!(evaluation_order(&specs).is_err());
+  }
+
+  #[test]
+  fn should_echo_the_patched_body() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message(
+      r#"{ "id": 7, "status": "NEW", "token": "t" }"#,
+      Headers::new(),
+    );
+    let action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {
+        "status".to_owned() => VarSpec::new(Var::Lit(Lit::Str("DONE".to_owned()))),
+      },
+      payload: PayloadTemplate::Echo(vec![
+        PatchOp::Set {
+          path: "$.status".to_owned(),
+          value: json!("{{ status }}"),
+        },
+        PatchOp::Remove {
+          path: "$.token".to_owned(),
+        },
+      ]),
+      headers: hashmap! {},
+      schedule: ScheduleSpec::from_secs(0),
+      on_error: FailurePolicy::Fail,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let output = handle_message(&action, &input, &evaluator).unwrap();
+
+    assert_eq!(
+      json!({ "id": 7, "status": "DONE" }),
+      serde_json::from_slice::<JsonValue>(&output.payload).unwrap()
+    );
+  }
+
+  #[test]
+  fn should_apply_the_failure_policy_when_echoing_a_malformed_body() {
+    let mut rng = rand::thread_rng();
+    let evaluator = Random::new(&mut rng);
+    let input = message("not json", Headers::new());
+    let mut action = ActionSpec {
+      to: RouteSpec {
+        exchange: Some("x".to_owned()),
+        routing_key: Some("r.k".to_owned()),
+        reply: false,
+      },
+      variables: hashmap! {},
+      payload: PayloadTemplate::Echo(vec![PatchOp::Remove {
+        path: "$.token".to_owned(),
+      }]),
+      headers: hashmap! {},
+      schedule: ScheduleSpec::from_secs(0),
+      on_error: FailurePolicy::Skip,
+      repeat: None,
+      faults: None,
+      toxic: None,
+    };
+
+    let skipped = handle_message(&action, &input, &evaluator).unwrap_err();
+    assert!(skipped.downcast_ref::<ActionSkipped>().is_some());
+
+    action.on_error = FailurePolicy::Publish(ErrorMessageSpec {
+      to: None,
+      payload: PayloadTemplate::Inline("Failed: {{ error }}".to_owned()),
+      headers: hashmap! {},
+    });
+    let output = handle_message(&action, &input, &evaluator).unwrap();
+    assert!(String::from_utf8(output.payload)
+      .unwrap()
+      .starts_with("Failed: Cannot patch the incoming body"));
+  }
+
+  #[test]
+  fn should_share_the_parsed_body_between_clones() {
+    let input = message(r#"{ "id": 7 }"#, Headers::new());
+    let clone = input.clone();
+
+    let parsed = input.json_body.parse(&input.payload).unwrap();
+
+    assert!(Arc::ptr_eq(
+      &parsed,
+      &clone.json_body.parse(&clone.payload).unwrap()
+    ));
+    assert!(message("not json", Headers::new())
+      .json_body
+      .parse(b"not json")
+      .is_err());
+  }
 }
@@ -0,0 +1,104 @@
+use failure::Error;
+use jsonpath::Selector;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value as JsonValue;
+use std::fmt;
+use std::sync::Arc;
+
+/// A json path, whose selector is compiled once, when the configuration is loaded.
+#[derive(Clone)]
+pub struct JsonPath {
+  path: String,
+  selector: Option<Arc<Selector>>,
+}
+
+impl JsonPath {
+  pub fn as_str(&self) -> &str {
+    &self.path
+  }
+
+  /// Checks that the path is valid.
+  pub fn validate(&self) -> Result<(), Error> {
+    self.selector().map(|_| ())
+  }
+
+  /// Compiles the selector, so that it is not compiled again for each message.
+  pub fn compile(&mut self) -> Result<(), Error> {
+    let selector = self.selector()?;
+    self.selector = Some(selector);
+    Ok(())
+  }
+
+  /// The values matched by the path in the document.
+  pub fn find(&self, document: &JsonValue) -> Result<Vec<JsonValue>, Error> {
+    Ok(self.selector()?.find(document).cloned().collect())
+  }
+
+  fn selector(&self) -> Result<Arc<Selector>, Error> {
+    match self.selector {
+      Some(ref selector) => Ok(selector.clone()),
+      None => Selector::new(&self.path)
+        .map(Arc::new)
+        .map_err(|e| format_err!("Invalid json path {}: {}", self.path, e)),
+    }
+  }
+}
+
+impl<'a> From<&'a str> for JsonPath {
+  fn from(path: &str) -> JsonPath {
+    JsonPath {
+      path: path.to_owned(),
+      selector: None,
+    }
+  }
+}
+
+/// Two paths are equal when they are written the same, whether they are compiled or not.
+impl PartialEq for JsonPath {
+  fn eq(&self, other: &JsonPath) -> bool {
+    self.path == other.path
+  }
+}
+
+impl fmt::Debug for JsonPath {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self.path)
+  }
+}
+
+impl fmt::Display for JsonPath {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.path)
+  }
+}
+
+impl<'de> Deserialize<'de> for JsonPath {
+  fn deserialize<D>(deserializer: D) -> Result<JsonPath, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    String::deserialize(deserializer).map(|path| JsonPath::from(path.as_str()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn should_find_values_with_a_compiled_path() {
+    let mut path = JsonPath::from("$.items.*.sku");
+
+    path.compile().unwrap();
+
+    assert_eq!(
+      vec![json!("A1"), json!("B2")],
+      path
+        .find(&json!({ "items": [{ "sku": "A1" }, { "sku": "B2" }] }))
+        .unwrap()
+    );
+    assert_eq!(JsonPath::from("$.items.*.sku"), path);
+    assert!(JsonPath::from("$.items[").compile().is_err());
+  }
+}
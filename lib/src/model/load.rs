@@ -0,0 +1,295 @@
+use super::delay;
+use failure::{err_msg, Error};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Publishes messages at a target rate, to load-test a consumer.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct LoadSpec {
+  /// The target number of messages per second.
+  pub rate: f64,
+  /// The duration over which the rate grows linearly from 0 to `rate`.
+  #[serde(default)]
+  pub ramp_up: Option<String>,
+  /// The load stops after this duration, or after `count` messages, whichever comes first.
+  #[serde(default)]
+  pub duration: Option<String>,
+  #[serde(default)]
+  pub count: Option<u64>,
+  /// The number of channels the messages are published on.
+  #[serde(default = "one")]
+  pub channels: usize,
+}
+
+fn one() -> usize {
+  1
+}
+
+fn secs(duration: &Duration) -> f64 {
+  duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn from_secs(secs: f64) -> Duration {
+  Duration::from_micros((secs.max(0.0) * 1e6).round() as u64)
+}
+
+impl LoadSpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    if !self.rate.is_finite() || self.rate <= 0.0 {
+      return Err(format_err!("The rate must be positive, not {}", self.rate));
+    }
+    if self.duration.is_none() && self.count.is_none() {
+      return Err(err_msg("A load needs a duration or a count"));
+    }
+    if self.channels == 0 {
+      return Err(err_msg("A load needs at least one channel"));
+    }
+    for duration in self.ramp_up.iter().chain(self.duration.iter()) {
+      delay::validate_duration(duration)?;
+    }
+    Ok(())
+  }
+
+  fn ramp_up_secs(&self) -> Result<f64, Error> {
+    match self.ramp_up {
+      Some(ref ramp_up) => delay::to_std(ramp_up).map(|d| secs(&d)),
+      None => Ok(0.0),
+    }
+  }
+
+  /// When the message of the given index, from 0, is due, from the start of the load.
+  pub fn due(&self, index: u64) -> Result<Duration, Error> {
+    let ramp_up = self.ramp_up_secs()?;
+    let n = index as f64;
+    // During the ramp up, rate * t² / (2 * ramp_up) messages are due at t.
+    let t = if n < self.rate * ramp_up / 2.0 {
+      (2.0 * ramp_up * n / self.rate).sqrt()
+    } else {
+      n / self.rate + ramp_up / 2.0
+    };
+    Ok(from_secs(t))
+  }
+
+  /// The number of messages to publish.
+  pub fn total(&self) -> Result<u64, Error> {
+    let within_duration = match self.duration {
+      Some(ref duration) => {
+        let duration = delay::to_std(duration)?;
+        let mut low = 0;
+        let mut high = u64::max_value() / 2;
+        // The first index due after the end.
+        while low < high {
+          let mid = low + (high - low) / 2;
+          if self.due(mid)? >= duration {
+            high = mid;
+          } else {
+            low = mid + 1;
+          }
+        }
+        Some(low)
+      }
+      None => None,
+    };
+    Ok(match (within_duration, self.count) {
+      (Some(d), Some(c)) => d.min(c),
+      (Some(d), None) => d,
+      (None, Some(c)) => c,
+      (None, None) => 0,
+    })
+  }
+}
+
+/// What happened during a load.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadReport {
+  pub published: u64,
+  pub failed: u64,
+  /// The publish latencies, as seen by the client.
+  pub latencies: LatencyHistogram,
+  pub elapsed: Duration,
+}
+
+impl LoadReport {
+  pub fn record(&mut self, published: bool, latency: Duration) {
+    if published {
+      self.published += 1;
+      self.latencies.record(latency);
+    } else {
+      self.failed += 1;
+    }
+  }
+
+  /// The achieved number of messages per second.
+  pub fn throughput(&self) -> f64 {
+    match secs(&self.elapsed) {
+      s if s > 0.0 => self.published as f64 / s,
+      _ => 0.0,
+    }
+  }
+
+  /// The latency below which the given percentage of the messages were published.
+  pub fn latency(&self, percentile: f64) -> Option<Duration> {
+    self.latencies.percentile(percentile)
+  }
+}
+
+/// Latencies counted by buckets of 3 significant digits, in microseconds: the histogram holds a few thousand buckets at
+/// most, however long the load, for a precision of 0.1%.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+  buckets: BTreeMap<u64, u64>,
+  count: u64,
+  max: Duration,
+}
+
+impl LatencyHistogram {
+  pub fn record(&mut self, latency: Duration) {
+    let micros = latency.as_secs() * 1_000_000 + u64::from(latency.subsec_micros());
+    *self.buckets.entry(bucket(micros)).or_insert(0) += 1;
+    self.count += 1;
+    self.max = self.max.max(latency);
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count
+  }
+
+  /// The latency below which the given percentage of the samples are, rounded down to its bucket. 100 gives the
+  /// exact maximum.
+  pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+    if self.count == 0 {
+      return None;
+    }
+    let rank = (percentile / 100.0 * self.count as f64).ceil() as u64;
+    if rank >= self.count {
+      return Some(self.max);
+    }
+    let mut seen = 0;
+    for (&bucket, &count) in self.buckets.iter() {
+      seen += count;
+      if seen >= rank.max(1) {
+        return Some(Duration::from_micros(bucket));
+      }
+    }
+    Some(self.max)
+  }
+}
+
+/// The number rounded down to 3 significant digits.
+fn bucket(micros: u64) -> u64 {
+  let mut scale = 1;
+  while micros / scale >= 1000 {
+    scale *= 10;
+  }
+  micros / scale * scale
+}
+
+impl fmt::Display for LoadReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} messages published, {} failed in {:?} ({:.1} msg/s)",
+      self.published,
+      self.failed,
+      self.elapsed,
+      self.throughput()
+    )?;
+    if let (Some(p50), Some(p99), Some(max)) =
+      (self.latency(50.0), self.latency(99.0), self.latency(100.0))
+    {
+      write!(
+        f,
+        ", publish latency p50 {:?}, p99 {:?}, max {:?}",
+        p50, p99, max
+      )?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  fn load(ramp_up: Option<&str>, duration: Option<&str>, count: Option<u64>) -> LoadSpec {
+    LoadSpec {
+      rate: 100.0,
+      ramp_up: ramp_up.map(str::to_owned),
+      duration: duration.map(str::to_owned),
+      count,
+      channels: 1,
+    }
+  }
+
+  #[test]
+  fn should_pace_the_messages() {
+    let steady = load(None, Some("10s"), None);
+    let ramping = load(Some("2s"), Some("10s"), Some(500));
+
+    assert_eq!(Duration::from_millis(10), steady.due(1).unwrap());
+    assert_eq!(1000, steady.total().unwrap());
+    // 100 messages are due during the ramp up, the first 25 during its first second.
+    assert_eq!(Duration::from_secs(1), ramping.due(25).unwrap());
+    assert_eq!(Duration::from_secs(2), ramping.due(100).unwrap());
+    assert_eq!(Duration::from_millis(2010), ramping.due(101).unwrap());
+    assert_eq!(500, ramping.total().unwrap());
+    assert_eq!(900, load(Some("2s"), Some("10s"), None).total().unwrap());
+  }
+
+  #[test]
+  fn should_report_throughput_and_latencies() {
+    let mut report = LoadReport::default();
+    for ms in 1..=100 {
+      report.record(true, Duration::from_millis(ms));
+    }
+    report.record(false, Duration::from_secs(0));
+    report.elapsed = Duration::from_secs(4);
+
+    assert_eq!(25.0, report.throughput());
+    assert_eq!(Some(Duration::from_millis(50)), report.latency(50.0));
+    assert_eq!(Some(Duration::from_millis(99)), report.latency(99.0));
+    assert_eq!(Some(Duration::from_millis(100)), report.latency(100.0));
+    assert_eq!(1, report.failed);
+  }
+
+  #[test]
+  fn should_keep_latencies_in_a_bounded_histogram() {
+    let mut histogram = LatencyHistogram::default();
+    for us in 0..100_000 {
+      histogram.record(Duration::from_micros(us * 37));
+    }
+
+    assert_eq!(100_000, histogram.count());
+    assert!(histogram.buckets.len() <= 3000);
+    assert_eq!(
+      Some(Duration::from_micros(1_840_000)),
+      histogram.percentile(50.0)
+    );
+    assert_eq!(
+      Some(Duration::from_micros(99_999 * 37)),
+      histogram.percentile(100.0)
+    );
+    assert_eq!(None, LatencyHistogram::default().percentile(50.0));
+  }
+
+  #[test]
+  fn should_reject_an_endless_load() {
+    assert!(load(None, None, None).validate().is_err());
+    assert!(LoadSpec {
+      rate: 0.0,
+      ..load(None, None, Some(10))
+    }
+    .validate()
+    .is_err());
+    for rate in &[-1.0, ::std::f64::INFINITY, ::std::f64::NAN] {
+      assert!(LoadSpec {
+        rate: *rate,
+        ..load(None, None, Some(10))
+      }
+      .validate()
+      .is_err());
+    }
+  }
+}
@@ -1 +1,12 @@
+pub mod admin;
+pub mod delay;
+pub mod engine;
+pub mod expr;
+pub mod fake;
+pub mod fault;
 pub mod imposter;
+pub mod json_path;
+pub mod load;
+pub mod patch;
+pub mod time;
+pub mod toxic;
@@ -0,0 +1,465 @@
+use failure::{err_msg, Error};
+use serde_json::{Map, Value as JsonValue};
+
+/// An operation applied to the incoming json body. Values are json templates (see `JsonTemplate`).
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "op")]
+pub enum PatchOp {
+  Set { path: String, value: JsonValue },
+  Remove { path: String },
+  Rename { from: String, to: String },
+  JsonPatch { patch: Vec<JsonPatchOp> },
+  MergePatch { patch: JsonValue },
+}
+
+/// An RFC 6902 operation.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JsonPatchOp {
+  Add { path: String, value: JsonValue },
+  Remove { path: String },
+  Replace { path: String, value: JsonValue },
+  Move { from: String, path: String },
+  Copy { from: String, path: String },
+  Test { path: String, value: JsonValue },
+}
+
+impl PatchOp {
+  /// Checks the paths and the pointers of the operation.
+  pub fn validate(&self) -> Result<(), Error> {
+    match self {
+      PatchOp::Set { path, .. } | PatchOp::Remove { path } => parse_path(path).map(|_| ()),
+      PatchOp::Rename { from, to } => parse_path(from).and_then(|_| parse_path(to)).map(|_| ()),
+      PatchOp::JsonPatch { patch } => patch
+        .iter()
+        .map(|op| match op {
+          JsonPatchOp::Move { from, path } | JsonPatchOp::Copy { from, path } => {
+            parse_pointer(from).and_then(|_| parse_pointer(path))
+          }
+          JsonPatchOp::Add { path, .. }
+          | JsonPatchOp::Remove { path }
+          | JsonPatchOp::Replace { path, .. }
+          | JsonPatchOp::Test { path, .. } => parse_pointer(path),
+        })
+        .map(|parsed| parsed.map(|_| ()))
+        .collect(),
+      PatchOp::MergePatch { .. } => Ok(()),
+    }
+  }
+
+  /// Returns the same operation, with its values transformed by `fill`.
+  pub fn map_values<F>(&self, fill: F) -> Result<PatchOp, Error>
+  where
+    F: Fn(&JsonValue) -> Result<JsonValue, Error>,
+  {
+    Ok(match self {
+      PatchOp::Set { path, value } => PatchOp::Set {
+        path: path.clone(),
+        value: fill(value)?,
+      },
+      PatchOp::JsonPatch { patch } => PatchOp::JsonPatch {
+        patch: patch
+          .iter()
+          .map(|op| {
+            Ok(match op {
+              JsonPatchOp::Add { path, value } => JsonPatchOp::Add {
+                path: path.clone(),
+                value: fill(value)?,
+              },
+              JsonPatchOp::Replace { path, value } => JsonPatchOp::Replace {
+                path: path.clone(),
+                value: fill(value)?,
+              },
+              JsonPatchOp::Test { path, value } => JsonPatchOp::Test {
+                path: path.clone(),
+                value: fill(value)?,
+              },
+              op => op.clone(),
+            })
+          })
+          .collect::<Result<Vec<JsonPatchOp>, Error>>()?,
+      },
+      PatchOp::MergePatch { patch } => PatchOp::MergePatch {
+        patch: fill(patch)?,
+      },
+      op => op.clone(),
+    })
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+  Key(String),
+  Index(usize),
+}
+
+/// Parses a simple json path, e.g. `$.order.lines[0].sku` or `$['key.with.dots']`.
+fn parse_path(path: &str) -> Result<Vec<Segment>, Error> {
+  let invalid = || format_err!("Invalid json path {}", path);
+  let mut rest = path.trim();
+  if rest.starts_with('$') {
+    rest = &rest[1..];
+  }
+  let mut segments = Vec::new();
+  while !rest.is_empty() {
+    if rest.starts_with('[') {
+      let end = rest.find(']').ok_or_else(invalid)?;
+      let inner = &rest[1..end];
+      let quoted = inner.len() >= 2
+        && (inner.starts_with('\'') && inner.ends_with('\'')
+          || inner.starts_with('"') && inner.ends_with('"'));
+      if quoted {
+        segments.push(Segment::Key(inner[1..inner.len() - 1].to_owned()));
+      } else {
+        segments.push(Segment::Index(
+          inner.parse::<usize>().map_err(|_| invalid())?,
+        ));
+      }
+      rest = &rest[end + 1..];
+    } else if rest.starts_with('.') {
+      let end = rest[1..]
+        .find(|c| c == '.' || c == '[')
+        .map(|i| i + 1)
+        .unwrap_or(rest.len());
+      if end == 1 {
+        return Err(invalid());
+      }
+      segments.push(Segment::Key(rest[1..end].to_owned()));
+      rest = &rest[end..];
+    } else {
+      return Err(invalid());
+    }
+  }
+  Ok(segments)
+}
+
+/// Parses an RFC 6901 json pointer, e.g. `/order/lines/0`.
+fn parse_pointer(pointer: &str) -> Result<Vec<Segment>, Error> {
+  if pointer.is_empty() {
+    return Ok(Vec::new());
+  }
+  if !pointer.starts_with('/') {
+    return Err(format_err!("Invalid json pointer {}", pointer));
+  }
+  Ok(
+    pointer[1..]
+      .split('/')
+      .map(|token| {
+        let key = token.replace("~1", "/").replace("~0", "~");
+        match key.parse::<usize>() {
+          Ok(i) if !key.starts_with('+') && (key == "0" || !key.starts_with('0')) => {
+            Segment::Index(i)
+          }
+          _ => Segment::Key(key),
+        }
+      })
+      .collect(),
+  )
+}
+
+fn get<'a>(doc: &'a JsonValue, segments: &[Segment]) -> Option<&'a JsonValue> {
+  segments
+    .iter()
+    .fold(Some(doc), |value, segment| match (value, segment) {
+      (Some(JsonValue::Object(o)), Segment::Key(k)) => o.get(k),
+      (Some(JsonValue::Object(o)), Segment::Index(i)) => o.get(&i.to_string()),
+      (Some(JsonValue::Array(a)), Segment::Index(i)) => a.get(*i),
+      _ => None,
+    })
+}
+
+fn parent_mut<'a>(
+  doc: &'a mut JsonValue,
+  segments: &[Segment],
+  create: bool,
+) -> Option<&'a mut JsonValue> {
+  segments.iter().fold(Some(doc), |value, segment| {
+    let value = value?;
+    if create && value.is_null() {
+      *value = JsonValue::Object(Map::new());
+    }
+    match (value, segment) {
+      (JsonValue::Object(o), Segment::Key(k)) => Some(if create {
+        o.entry(k.clone()).or_insert(JsonValue::Null)
+      } else {
+        o.get_mut(k)?
+      }),
+      (JsonValue::Object(o), Segment::Index(i)) => o.get_mut(&i.to_string()),
+      (JsonValue::Array(a), Segment::Index(i)) => a.get_mut(*i),
+      _ => None,
+    }
+  })
+}
+
+/// Inserts a value. When `append` is set, a value inserted in an array shifts the next elements,
+/// otherwise it replaces the existing one.
+fn insert(
+  doc: &mut JsonValue,
+  segments: &[Segment],
+  value: JsonValue,
+  create: bool,
+  append: bool,
+) -> Result<(), Error> {
+  let (last, parents) = match segments.split_last() {
+    Some(split) => split,
+    None => {
+      *doc = value;
+      return Ok(());
+    }
+  };
+  let parent =
+    parent_mut(doc, parents, create).ok_or_else(|| err_msg("The parent does not exist"))?;
+  if create && parent.is_null() {
+    *parent = JsonValue::Object(Map::new());
+  }
+  match (parent, last) {
+    (JsonValue::Object(o), Segment::Key(k)) => {
+      o.insert(k.clone(), value);
+      Ok(())
+    }
+    (JsonValue::Object(o), Segment::Index(i)) => {
+      o.insert(i.to_string(), value);
+      Ok(())
+    }
+    (JsonValue::Array(a), Segment::Index(i)) if append && *i <= a.len() => {
+      a.insert(*i, value);
+      Ok(())
+    }
+    (JsonValue::Array(a), Segment::Index(i)) if *i < a.len() => {
+      a[*i] = value;
+      Ok(())
+    }
+    (JsonValue::Array(a), Segment::Index(i)) if *i == a.len() => {
+      a.push(value);
+      Ok(())
+    }
+    (JsonValue::Array(a), Segment::Key(ref k)) if append && k == "-" => {
+      a.push(value);
+      Ok(())
+    }
+    _ => Err(err_msg("The parent cannot hold the value")),
+  }
+}
+
+fn remove(doc: &mut JsonValue, segments: &[Segment]) -> Option<JsonValue> {
+  let (last, parents) = segments.split_last()?;
+  match (parent_mut(doc, parents, false)?, last) {
+    (JsonValue::Object(o), Segment::Key(k)) => o.remove(k),
+    (JsonValue::Object(o), Segment::Index(i)) => o.remove(&i.to_string()),
+    (JsonValue::Array(a), Segment::Index(i)) if *i < a.len() => Some(a.remove(*i)),
+    _ => None,
+  }
+}
+
+fn merge(doc: &mut JsonValue, patch: JsonValue) {
+  match patch {
+    JsonValue::Object(fields) => {
+      if !doc.is_object() {
+        *doc = JsonValue::Object(Map::new());
+      }
+      if let JsonValue::Object(o) = doc {
+        for (k, v) in fields {
+          if v.is_null() {
+            o.remove(&k);
+          } else {
+            merge(o.entry(k).or_insert(JsonValue::Null), v);
+          }
+        }
+      }
+    }
+    p => *doc = p,
+  }
+}
+
+fn apply_json_patch(doc: &mut JsonValue, op: JsonPatchOp) -> Result<(), Error> {
+  match op {
+    JsonPatchOp::Add { path, value } => insert(doc, &parse_pointer(&path)?, value, false, true)
+      .map_err(|e| format_err!("Cannot add {}: {}", path, e)),
+    JsonPatchOp::Remove { path } => remove(doc, &parse_pointer(&path)?)
+      .map(|_| ())
+      .ok_or_else(|| format_err!("Cannot remove {}: no such value", path)),
+    JsonPatchOp::Replace { path, value } => {
+      let segments = parse_pointer(&path)?;
+      match parent_mut(doc, &segments, false) {
+        Some(target) => {
+          *target = value;
+          Ok(())
+        }
+        None => Err(format_err!("Cannot replace {}: no such value", path)),
+      }
+    }
+    JsonPatchOp::Move { from, path } => {
+      let value = remove(doc, &parse_pointer(&from)?)
+        .ok_or_else(|| format_err!("Cannot move {}: no such value", from))?;
+      insert(doc, &parse_pointer(&path)?, value, false, true)
+        .map_err(|e| format_err!("Cannot move {} to {}: {}", from, path, e))
+    }
+    JsonPatchOp::Copy { from, path } => {
+      let value = get(doc, &parse_pointer(&from)?)
+        .cloned()
+        .ok_or_else(|| format_err!("Cannot copy {}: no such value", from))?;
+      insert(doc, &parse_pointer(&path)?, value, false, true)
+        .map_err(|e| format_err!("Cannot copy {} to {}: {}", from, path, e))
+    }
+    JsonPatchOp::Test { path, value } => match get(doc, &parse_pointer(&path)?) {
+      Some(v) if *v == value => Ok(()),
+      v => Err(format_err!(
+        "Test failed on {}: expected {}, got {}",
+        path,
+        value,
+        v.unwrap_or(&JsonValue::Null)
+      )),
+    },
+  }
+}
+
+/// Applies an operation, whose values are already filled, to the document.
+pub fn apply(doc: &mut JsonValue, op: PatchOp) -> Result<(), Error> {
+  match op {
+    PatchOp::Set { path, value } => insert(doc, &parse_path(&path)?, value, true, false)
+      .map_err(|e| format_err!("Cannot set {}: {}", path, e)),
+    PatchOp::Remove { path } => {
+      remove(doc, &parse_path(&path)?);
+      Ok(())
+    }
+    PatchOp::Rename { from, to } => match remove(doc, &parse_path(&from)?) {
+      Some(value) => insert(doc, &parse_path(&to)?, value, true, false)
+        .map_err(|e| format_err!("Cannot rename {} to {}: {}", from, to, e)),
+      None => Ok(()),
+    },
+    PatchOp::JsonPatch { patch } => patch
+      .into_iter()
+      .map(|op| apply_json_patch(doc, op))
+      .collect(),
+    PatchOp::MergePatch { patch } => {
+      merge(doc, patch);
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn should_set_remove_and_rename() {
+    let mut doc = json!({ "id": 1, "order": { "lines": [{ "sku": "A1" }] }, "secret": "s" });
+
+    apply(
+      &mut doc,
+      PatchOp::Set {
+        path: "$.order.lines[0].qty".to_owned(),
+        value: json!(2),
+      },
+    )
+    .unwrap();
+    apply(
+      &mut doc,
+      PatchOp::Set {
+        path: "$.status.code".to_owned(),
+        value: json!("OK"),
+      },
+    )
+    .unwrap();
+    apply(
+      &mut doc,
+      PatchOp::Remove {
+        path: "$.secret".to_owned(),
+      },
+    )
+    .unwrap();
+    apply(
+      &mut doc,
+      PatchOp::Rename {
+        from: "$.id".to_owned(),
+        to: "$['request.id']".to_owned(),
+      },
+    )
+    .unwrap();
+
+    assert_eq!(
+      json!({
+        "request.id": 1,
+        "order": { "lines": [{ "sku": "A1", "qty": 2 }] },
+        "status": { "code": "OK" }
+      }),
+      doc
+    );
+  }
+
+  #[test]
+  fn should_apply_json_patch_and_merge_patch() {
+    let mut doc = json!({ "a": { "b": 1, "c": [1, 2] }, "d": "x" });
+
+    apply(
+      &mut doc,
+      PatchOp::JsonPatch {
+        patch: vec![
+          JsonPatchOp::Test {
+            path: "/a/b".to_owned(),
+            value: json!(1),
+          },
+          JsonPatchOp::Add {
+            path: "/a/c/1".to_owned(),
+            value: json!(9),
+          },
+          JsonPatchOp::Add {
+            path: "/a/c/-".to_owned(),
+            value: json!(3),
+          },
+          JsonPatchOp::Move {
+            from: "/d".to_owned(),
+            path: "/e".to_owned(),
+          },
+        ],
+      },
+    )
+    .unwrap();
+    apply(
+      &mut doc,
+      PatchOp::MergePatch {
+        patch: json!({ "a": { "b": null }, "f": true }),
+      },
+    )
+    .unwrap();
+
+    assert_eq!(
+      json!({ "a": { "c": [1, 9, 2, 3] }, "e": "x", "f": true }),
+      doc
+    );
+    assert!(apply(
+      &mut doc,
+      PatchOp::JsonPatch {
+        patch: vec![JsonPatchOp::Test {
+          path: "/e".to_owned(),
+          value: json!("y"),
+        }],
+      },
+    )
+    .is_err());
+  }
+
+  #[test]
+  fn should_validate_paths_and_pointers() {
+    let valid = PatchOp::Rename {
+      from: "$.id".to_owned(),
+      to: "$['request.id']".to_owned(),
+    };
+    let invalid_path = PatchOp::Set {
+      path: "$.lines[first]".to_owned(),
+      value: json!(1),
+    };
+    let invalid_pointer = PatchOp::JsonPatch {
+      patch: vec![JsonPatchOp::Copy {
+        from: "/a".to_owned(),
+        path: "b".to_owned(),
+      }],
+    };
+
+    assert!(valid.validate().is_ok());
+    assert!(invalid_path.validate().is_err());
+    assert!(invalid_pointer.validate().is_err());
+  }
+}
@@ -0,0 +1,294 @@
+use super::json_path::JsonPath;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Duration, FixedOffset, Local, TimeZone, Utc};
+use failure::Error;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct DateTimeSpec {
+  #[serde(default)]
+  pub format: DateFormat,
+  /// `UTC`, `Local` or a fixed offset like `+02:00`. Named zones such as `Europe/Paris` are not supported: a fixed
+  /// offset does not follow daylight saving time, and `Local` is the offset of the host when the date is formatted.
+  #[serde(default)]
+  pub timezone: Option<String>,
+  #[serde(default)]
+  pub from: DateBase,
+  #[serde(default)]
+  pub offset: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct TimestampSpec {
+  #[serde(default)]
+  pub unit: TimeUnit,
+  #[serde(default)]
+  pub from: DateBase,
+  #[serde(default)]
+  pub offset: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum DateFormat {
+  Rfc3339,
+  Rfc2822,
+  Custom(String),
+}
+
+impl Default for DateFormat {
+  fn default() -> DateFormat {
+    DateFormat::Rfc3339
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum TimeUnit {
+  #[serde(rename = "s")]
+  Seconds,
+  #[serde(rename = "ms")]
+  Millis,
+  #[serde(rename = "us", alias = "µs")]
+  Micros,
+  #[serde(rename = "ns")]
+  Nanos,
+}
+
+impl Default for TimeUnit {
+  fn default() -> TimeUnit {
+    TimeUnit::Nanos
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum DateBase {
+  Now,
+  Header(String),
+  JsonPath(JsonPath),
+}
+
+impl Default for DateBase {
+  fn default() -> DateBase {
+    DateBase::Now
+  }
+}
+
+impl DateTimeSpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    if let DateFormat::Custom(ref f) = self.format {
+      validate_format(f)?;
+    }
+    if let Some(ref tz) = self.timezone {
+      parse_timezone(tz)?;
+    }
+    validate_offset(&self.offset)
+  }
+}
+
+impl TimestampSpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    validate_offset(&self.offset)
+  }
+}
+
+fn validate_offset(offset: &Option<String>) -> Result<(), Error> {
+  match offset {
+    Some(o) => parse_duration(o).map(|_| ()),
+    None => Ok(()),
+  }
+}
+
+/// Checks a strftime format, which chrono would otherwise panic on when formatting.
+pub fn validate_format(format: &str) -> Result<(), Error> {
+  if StrftimeItems::new(format).any(|item| item == Item::Error) {
+    Err(format_err!("Invalid date format {}", format))
+  } else {
+    Ok(())
+  }
+}
+
+/// Parses a signed duration such as `2d`, `-30m`, `+1h30m` or `1m 30s 500ms`.
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+  let trimmed = s.trim();
+  let (sign, rest) = match trimmed.chars().next() {
+    Some('-') => (-1, &trimmed[1..]),
+    Some('+') => (1, &trimmed[1..]),
+    _ => (1, trimmed),
+  };
+  let mut total = Duration::zero();
+  let mut chars = rest.chars().filter(|c| !c.is_whitespace()).peekable();
+  if chars.peek().is_none() {
+    return Err(format_err!("Empty duration: {}", s));
+  }
+  while chars.peek().is_some() {
+    let mut number = String::new();
+    while let Some(c) = chars.peek().cloned().filter(char::is_ascii_digit) {
+      number.push(c);
+      chars.next();
+    }
+    let mut unit = String::new();
+    while let Some(c) = chars.peek().cloned().filter(|c| c.is_alphabetic()) {
+      unit.push(c);
+      chars.next();
+    }
+    let n = number
+      .parse::<i64>()
+      .map_err(|_| format_err!("Invalid duration: {}", s))?;
+    let seconds_per_unit = match unit.as_ref() {
+      "ms" => None,
+      "s" | "" => Some(1),
+      "m" | "min" => Some(60),
+      "h" => Some(3600),
+      "d" => Some(86_400),
+      "w" => Some(604_800),
+      u => return Err(format_err!("Unknown duration unit {} in {}", u, s)),
+    };
+    let part = match seconds_per_unit {
+      Some(factor) => n
+        .checked_mul(factor)
+        .filter(|secs| *secs <= Duration::max_value().num_seconds())
+        .map(Duration::seconds),
+      None => Some(Duration::milliseconds(n)),
+    };
+    total = part
+      .and_then(|part| total.checked_add(&part))
+      .ok_or_else(|| format_err!("Duration out of range: {}", s))?;
+  }
+  Ok(total * sign)
+}
+
+/// Parses a date given either as an RFC 3339 or an RFC 2822 string.
+pub fn parse_date(s: &str) -> Result<DateTime<FixedOffset>, Error> {
+  DateTime::parse_from_rfc3339(s)
+    .or_else(|_| DateTime::parse_from_rfc2822(s))
+    .map_err(|_| format_err!("Cannot parse date {}", s))
+}
+
+/// Converts an AMQP-style timestamp, in seconds, into a date.
+pub fn from_timestamp(seconds: i64) -> Result<DateTime<FixedOffset>, Error> {
+  Utc
+    .timestamp_opt(seconds, 0)
+    .single()
+    .map(|date| date.with_timezone(&FixedOffset::east(0)))
+    .ok_or_else(|| format_err!("Timestamp out of range: {}", seconds))
+}
+
+pub fn apply_offset(
+  date: DateTime<FixedOffset>,
+  offset: &Option<String>,
+) -> Result<DateTime<FixedOffset>, Error> {
+  match offset {
+    Some(o) => {
+      let duration = parse_duration(o)?;
+      date
+        .checked_add_signed(duration)
+        .ok_or_else(|| format_err!("Date overflow when adding {} to {}", o, date))
+    }
+    None => Ok(date),
+  }
+}
+
+fn parse_timezone(tz: &str) -> Result<FixedOffset, Error> {
+  match tz {
+    "UTC" | "Z" => Ok(FixedOffset::east(0)),
+    "Local" => Ok(*Local::now().offset()),
+    _ => parse_date(&format!("1970-01-01T00:00:00{}", tz))
+      .map(|d| *d.offset())
+      .map_err(|_| {
+        format_err!(
+          "Invalid time zone {}: expected UTC, Local or a fixed offset like +02:00",
+          tz
+        )
+      }),
+  }
+}
+
+pub fn format_date(date: DateTime<FixedOffset>, spec: &DateTimeSpec) -> Result<String, Error> {
+  let date = match spec.timezone {
+    Some(ref tz) => date.with_timezone(&parse_timezone(tz)?),
+    None => date,
+  };
+  match spec.format {
+    DateFormat::Rfc3339 => Ok(date.to_rfc3339()),
+    DateFormat::Rfc2822 => Ok(date.to_rfc2822()),
+    DateFormat::Custom(ref f) => validate_format(f).map(|_| date.format(f).to_string()),
+  }
+}
+
+pub fn to_timestamp(date: DateTime<FixedOffset>, unit: &TimeUnit) -> Result<i64, Error> {
+  let scaled = |per_second: i64, subsec: u32| {
+    date
+      .timestamp()
+      .checked_mul(per_second)
+      .and_then(|t| t.checked_add(i64::from(subsec)))
+  };
+  match unit {
+    TimeUnit::Seconds => Some(date.timestamp()),
+    TimeUnit::Millis => scaled(1000, date.timestamp_subsec_millis()),
+    TimeUnit::Micros => scaled(1_000_000, date.timestamp_subsec_micros()),
+    TimeUnit::Nanos => scaled(1_000_000_000, date.timestamp_subsec_nanos()),
+  }
+  .ok_or_else(|| format_err!("The date {} cannot be given in {:?}", date, unit))
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn should_parse_compound_durations() {
+    assert_eq!(Duration::seconds(90), parse_duration("1m30s").unwrap());
+    assert_eq!(Duration::days(-2), parse_duration("-2d").unwrap());
+    assert_eq!(
+      Duration::minutes(90) + Duration::milliseconds(5),
+      parse_duration("+1h 30m 5ms").unwrap()
+    );
+    assert!(parse_duration("3 fortnights").is_err());
+  }
+
+  #[test]
+  fn should_format_in_the_requested_time_zone() {
+    let spec = DateTimeSpec {
+      format: DateFormat::Custom("%Y-%m-%d %H:%M".to_owned()),
+      timezone: Some("+02:00".to_owned()),
+      from: DateBase::Now,
+      offset: None,
+    };
+    let date = apply_offset(
+      parse_date("2019-03-01T23:30:00Z").unwrap(),
+      &Some("1d".to_owned()),
+    )
+    .unwrap();
+
+    assert_eq!("2019-03-03 01:30", format_date(date, &spec).unwrap());
+    let named = DateTimeSpec {
+      timezone: Some("Europe/Paris".to_owned()),
+      ..spec
+    };
+    assert!(named
+      .validate()
+      .unwrap_err()
+      .to_string()
+      .contains("expected UTC, Local or a fixed offset"));
+  }
+
+  #[test]
+  fn should_fail_without_panicking_out_of_range() {
+    let spec = DateTimeSpec {
+      format: DateFormat::Custom("%Y-%Q".to_owned()),
+      timezone: None,
+      from: DateBase::Now,
+      offset: None,
+    };
+    let far = parse_date("3000-01-01T00:00:00Z").unwrap();
+
+    assert!(spec.validate().is_err());
+    assert!(format_date(far, &spec).is_err());
+    assert!(parse_duration("999999999999999d").is_err());
+    assert!(from_timestamp(i64::max_value()).is_err());
+    assert!(to_timestamp(far, &TimeUnit::Nanos).is_err());
+    assert_eq!(
+      32_503_680_000_000_000,
+      to_timestamp(far, &TimeUnit::Micros).unwrap()
+    );
+  }
+}
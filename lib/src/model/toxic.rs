@@ -0,0 +1,157 @@
+use super::delay;
+use super::time::parse_date;
+use chrono::Utc;
+use failure::{err_msg, Error};
+use std::time::Duration;
+
+/// Simulates a peer that disconnects: once triggered, the reactor stops consuming.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ToxicSpec {
+  pub effect: ToxicEffect,
+  pub trigger: ToxicTrigger,
+  /// The pause before the reactor consumes again. Without it, the reactor stays stopped.
+  #[serde(default)]
+  pub resume_after: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum ToxicEffect {
+  /// Closes the channel the reactor consumes on.
+  CloseChannel,
+  /// Cancels the consumer, leaving the channel open.
+  CancelConsumer,
+  /// Stops the heartbeats of the connection the reactor consumes through, and closes its socket without closing the
+  /// connection first.
+  DropConnection,
+}
+
+/// A toxic applied on demand, by an action or an admin command, rather than on a trigger.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ToxicCommand {
+  pub effect: ToxicEffect,
+  /// The pause before the reactor consumes again. Without it, the reactor stays stopped.
+  #[serde(default)]
+  pub resume_after: Option<String>,
+}
+
+impl ToxicCommand {
+  pub fn validate(&self) -> Result<(), Error> {
+    match self.resume_after {
+      Some(ref pause) => delay::validate_duration(pause),
+      None => Ok(()),
+    }
+  }
+
+  pub fn pause(&self) -> Result<Option<Duration>, Error> {
+    self
+      .resume_after
+      .as_ref()
+      .map(|pause| delay::to_std(pause))
+      .map_or(Ok(None), |pause| pause.map(Some))
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum ToxicTrigger {
+  /// After this number of incoming messages.
+  Messages(u64),
+  /// At this date, in RFC 3339 or RFC 2822.
+  At(String),
+}
+
+impl ToxicSpec {
+  pub fn validate(&self) -> Result<(), Error> {
+    match self.trigger {
+      ToxicTrigger::Messages(0) => return Err(err_msg("A toxic needs at least one message")),
+      ToxicTrigger::At(ref date) => {
+        parse_date(date)?;
+      }
+      _ => (),
+    }
+    self.command().validate()
+  }
+
+  /// The number of messages after which the toxic is triggered, if any.
+  pub fn messages(&self) -> Option<u64> {
+    match self.trigger {
+      ToxicTrigger::Messages(n) => Some(n),
+      ToxicTrigger::At(_) => None,
+    }
+  }
+
+  /// The delay before the toxic is triggered, if it is triggered at a date. A past date gives no delay.
+  pub fn deadline(&self) -> Result<Option<Duration>, Error> {
+    match self.trigger {
+      ToxicTrigger::At(ref date) => {
+        let ms = parse_date(date)?.timestamp_millis() - Utc::now().timestamp_millis();
+        Ok(Some(Duration::from_millis(ms.max(0) as u64)))
+      }
+      ToxicTrigger::Messages(_) => Ok(None),
+    }
+  }
+
+  pub fn pause(&self) -> Result<Option<Duration>, Error> {
+    self.command().pause()
+  }
+
+  /// What to apply once the toxic is triggered.
+  pub fn command(&self) -> ToxicCommand {
+    ToxicCommand {
+      effect: self.effect.clone(),
+      resume_after: self.resume_after.clone(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn should_compute_when_the_toxic_is_triggered() {
+    let toxic = ToxicSpec {
+      effect: ToxicEffect::CloseChannel,
+      trigger: ToxicTrigger::At("2019-03-01T23:30:00Z".to_owned()),
+      resume_after: Some("30s".to_owned()),
+    };
+
+    assert_eq!(Some(Duration::from_secs(0)), toxic.deadline().unwrap());
+    assert_eq!(None, toxic.messages());
+    assert_eq!(Some(Duration::from_secs(30)), toxic.pause().unwrap());
+
+    let toxic = ToxicSpec {
+      trigger: ToxicTrigger::Messages(3),
+      resume_after: None,
+      ..toxic
+    };
+
+    assert_eq!(None, toxic.deadline().unwrap());
+    assert_eq!(Some(3), toxic.messages());
+    assert_eq!(None, toxic.pause().unwrap());
+  }
+
+  #[test]
+  fn should_reject_invalid_toxics() {
+    let toxic = ToxicSpec {
+      effect: ToxicEffect::DropConnection,
+      trigger: ToxicTrigger::Messages(0),
+      resume_after: None,
+    };
+
+    assert!(toxic.validate().is_err());
+    assert!(ToxicSpec {
+      trigger: ToxicTrigger::At("tomorrow".to_owned()),
+      ..toxic.clone()
+    }
+    .validate()
+    .is_err());
+    assert!(ToxicSpec {
+      trigger: ToxicTrigger::Messages(1),
+      resume_after: Some("soon".to_owned()),
+      ..toxic
+    }
+    .validate()
+    .is_err());
+  }
+}
@@ -1,16 +1,24 @@
 use super::config::io;
 use cron::Schedule;
 use failure::{err_msg, Error};
-use futures::future::lazy;
-use futures::IntoFuture;
-use futures::Sink;
+use futures::future::{self, lazy, Either, Loop};
+use futures::stream::StreamFuture;
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::Stream;
+use futures::{task, Async, IntoFuture};
 use lapin_futures_rustls::{
   lapin, lapin::channel::*, lapin::message::Delivery, lapin::types::*, AMQPConnectionRustlsExt,
   AMQPStream,
 };
+use model::admin::{AdminCommand, AdminSpec};
+use model::fault::{FaultCounters, FaultsSpec, Publication};
 use model::imposter::{Lit::*, *};
+use model::load::{LoadReport, LoadSpec};
+use model::toxic::{ToxicCommand, ToxicEffect, ToxicSpec};
 use rand::thread_rng;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::prelude::Future;
@@ -18,17 +26,56 @@ use tokio::timer::Delay;
 
 fn bootstrap(imposter: Imposter) {
   tokio::run(lazy(|| {
-    let connection_str = imposter.connection.clone();
+    let Imposter {
+      connection,
+      reactors,
+      generators,
+      admin,
+    } = imposter;
+    let connection_str = connection.clone();
+    let mut registry = HashMap::new();
+    let reactors: Vec<_> = reactors
+      .into_iter()
+      .map(|reactor| {
+        let (sender, receiver) = mpsc::unbounded();
+        registry.insert(reactor.queue.clone(), sender.clone());
+        (reactor, sender, receiver)
+      })
+      .collect();
     let program = create_client(&connection_str)
       .and_then(move |client| {
         create_client(&connection_str).map(|publish_client| (client, publish_client))
       })
       .and_then(move |(client, publish_client)| {
-        futures::stream::iter_ok(imposter.reactors)
-          .for_each(move |reactor| {
+        for generator in generators {
+          match generator.load {
+            Some(load) => {
+              tokio::spawn(run_load(&connection, generator.action, load));
+            }
+            None => warn!(
+              "Cron generators are not handled yet, ignoring {:?}",
+              generator.cron
+            ),
+          }
+        }
+        let administered = admin.is_some();
+        if let Some(admin) = admin {
+          tokio::spawn(
+            run_admin(&client, admin, registry).map_err(|e| error!("Admin error: {}", e)),
+          );
+        }
+        futures::stream::iter_ok(reactors)
+          .for_each(move |(reactor, sender, receiver)| {
             tokio::spawn(
-              create_reactor(&client, &publish_client, reactor)
-                .map_err(|e| error!("Reactor error: {}", e)),
+              create_reactor(
+                &connection,
+                &client,
+                &publish_client,
+                reactor,
+                (sender, receiver),
+                administered,
+              )
+              .map_err(|e| error!("Reactor error: {}", e)),
             )
           })
           .into_future()
@@ -50,27 +97,247 @@ fn bootstrap(imposter: Imposter) {
   }));
 }
 
+/// Consumes the admin commands, and hands each toxic to the reactor consuming the queue it names.
+fn run_admin(
+  client: &lapin::client::Client<AMQPStream>,
+  admin: AdminSpec,
+  reactors: HashMap<QueueName, UnboundedSender<ToxicCommand>>,
+) -> impl Future<Item = (), Error = Error> {
+  client
+    .create_channel()
+    .map_err(Error::from)
+    .and_then(move |channel| {
+      debug!("Declaring the admin queue {}", &admin.queue);
+      channel
+        .queue_declare(
+          &admin.queue,
+          QueueDeclareOptions {
+            auto_delete: true,
+            ..Default::default()
+          },
+          FieldTable::new(),
+        )
+        .map(move |queue| (channel, queue, admin))
+        .map_err(Error::from)
+    })
+    .and_then(|(channel, queue, admin)| {
+      channel
+        .queue_bind(
+          &queue.name(),
+          &admin.exchange,
+          &admin.routing_key,
+          QueueBindOptions::default(),
+          FieldTable::new(),
+        )
+        .map(move |_| (channel, queue))
+        .map_err(Error::from)
+    })
+    .and_then(|(channel, queue)| {
+      channel
+        .basic_consume(
+          &queue,
+          "",
+          BasicConsumeOptions::default(),
+          FieldTable::new(),
+        )
+        .map(move |stream| (channel, stream, queue.name()))
+        .map_err(Error::from)
+    })
+    .and_then(move |(channel, stream, queue_name)| {
+      info!("Waiting for admin commands on {}", queue_name);
+      stream.map_err(Error::from).for_each(move |delivery| {
+        let delivery_tag = delivery.delivery_tag;
+        match AdminCommand::parse(&delivery.data) {
+          Ok(AdminCommand::Toxic { queue, toxic }) => match reactors.get(&queue) {
+            Some(toxics) => request_toxic(toxics, &queue, toxic),
+            None => warn!("No reactor consumes {}, ignoring the toxic", queue),
+          },
+          Err(e) => warn!("{}", e),
+        }
+        channel.basic_ack(delivery_tag, false).map_err(Error::from)
+      })
+    })
+}
+
+/// Hands the toxic to the reactor consuming the queue.
+fn request_toxic(toxics: &UnboundedSender<ToxicCommand>, queue: &str, toxic: ToxicCommand) {
+  info!("Toxic requested on {}: {:?}", queue, toxic.effect);
+  if toxics.unbounded_send(toxic).is_err() {
+    warn!("The reactor on {} is stopped, ignoring the toxic", queue);
+  }
+}
+
+/// The toxics requested by the actions and the admin commands, as awaited by a reactor.
+type ToxicCommands = StreamFuture<UnboundedReceiver<ToxicCommand>>;
+
+/// A connection of its own, which a toxic can drop without disturbing the other reactors.
+struct DedicatedConnection {
+  client: lapin::client::Client<AMQPStream>,
+  heartbeat: lapin::client::HeartbeatHandle,
+}
+
+/// What a reactor keeps from one consumption to the next: its counters, its publisher and its dedicated connection.
+#[derive(Clone)]
+struct Reactor {
+  spec: ReactorSpec,
+  connection: String,
+  client: lapin::client::Client<AMQPStream>,
+  publisher: Publisher,
+  received: Arc<AtomicUsize>,
+  malformed: Arc<AtomicUsize>,
+  /// Set when a toxic may drop the connection the reactor consumes through.
+  dedicated: Option<Arc<Mutex<Option<DedicatedConnection>>>>,
+}
+
+/// How a consumption ended.
+struct Stopped {
+  /// The toxic applied, if any.
+  command: Option<ToxicCommand>,
+  /// The toxic of the reactor, unless it is the one applied.
+  armed: Option<ToxicSpec>,
+  /// The channel whose consumer is cancelled, to close when the reactor resumes.
+  cancelled: Option<Channel<AMQPStream>>,
+  commands: ToxicCommands,
+}
+
 fn create_reactor(
+  connection: &str,
   client: &lapin::client::Client<AMQPStream>,
   publish_client: &lapin::client::Client<AMQPStream>,
   reactor: ReactorSpec,
+  toxics: (
+    UnboundedSender<ToxicCommand>,
+    UnboundedReceiver<ToxicCommand>,
+  ),
+  administered: bool,
 ) -> impl Future<Item = (), Error = Error> {
-  let rk = reactor.routing_key.clone();
-  let xchg = reactor.exchange.clone();
-  let q = reactor.queue.clone();
-  let action = reactor.action.clone();
+  let drops_connection = administered
+    || reactor
+      .toxic
+      .iter()
+      .map(|toxic| &toxic.effect)
+      .chain(
+        reactor
+          .action
+          .iter()
+          .filter_map(|action| action.toxic.as_ref())
+          .map(|toxic| &toxic.effect),
+      )
+      .any(|effect| *effect == ToxicEffect::DropConnection);
+  let connection = connection.to_owned();
   let client = client.clone();
-  let publish_client = publish_client.clone();
-  client
+  let (sender, receiver) = toxics;
+  publish_client
     .create_channel()
     .map_err(Error::from)
-    .and_then(move |channel| {
-      publish_client
-        .create_channel()
-        .map(|publisher_channel| (publisher_channel, channel))
-        .map_err(Error::from)
+    .and_then(move |publisher_channel| {
+      let publisher = Publisher {
+        channel: Arc::new(Mutex::new(publisher_channel)),
+        queue: reactor.queue.clone(),
+        faults: reactor.faults.clone(),
+        counters: Arc::new(FaultCounters::default()),
+        toxics: sender,
+      };
+      let toxic = reactor.toxic.clone();
+      let state = Reactor {
+        spec: reactor,
+        connection,
+        client,
+        publisher,
+        received: Arc::new(AtomicUsize::new(0)),
+        malformed: Arc::new(AtomicUsize::new(0)),
+        dedicated: if drops_connection {
+          Some(Arc::new(Mutex::new(None)))
+        } else {
+          None
+        },
+      };
+      future::loop_fn((toxic, receiver.into_future()), move |(toxic, commands)| {
+        let queue = state.spec.queue.clone();
+        consume(state.clone(), toxic, commands).and_then(move |stopped| {
+          let Stopped {
+            command,
+            armed,
+            cancelled,
+            commands,
+          } = stopped;
+          match command.as_ref().map(ToxicCommand::pause) {
+            Some(Ok(Some(pause))) => Either::A(
+              Delay::new(Instant::now() + pause)
+                .map_err(Error::from)
+                .and_then(move |_| {
+                  info!("Resuming the reactor on {}", queue);
+                  match cancelled {
+                    Some(channel) => Either::A(
+                      channel
+                        .close(200, "Resuming the reactor")
+                        .map_err(Error::from),
+                    ),
+                    None => Either::B(future::ok(())),
+                  }
+                })
+                .map(move |_| Loop::Continue((armed, commands))),
+            ),
+            _ => Either::B(future::ok(Loop::Break(()))),
+          }
+        })
+      })
     })
-    .and_then(move |(publisher_channel, channel)| {
+}
+
+/// Consumes the incoming messages of the reactor, until its toxic, if any, is triggered, or an action or an admin
+/// command requests a toxic. Resolves to the toxic applied, if any.
+fn consume(
+  reactor: Reactor,
+  toxic: Option<ToxicSpec>,
+  commands: ToxicCommands,
+) -> impl Future<Item = Stopped, Error = Error> {
+  let Reactor {
+    spec,
+    connection,
+    client,
+    publisher,
+    received,
+    malformed,
+    dedicated,
+  } = reactor;
+  let rk = spec.routing_key.clone();
+  let xchg = spec.exchange.clone();
+  let q = spec.queue.clone();
+  let action = spec.action.clone();
+  let mode = spec.mode.clone();
+  let reads_json = action.iter().any(ActionSpec::reads_json_body);
+  let consumer_tag = format!("lapimposteur-{}", q);
+  let toxic_tag = consumer_tag.clone();
+  let dedicated_connection = dedicated.clone().unwrap_or_default();
+  let consumer_client = match dedicated {
+    Some(slot) => {
+      let opened = slot
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|dedicated| dedicated.client.clone());
+      match opened {
+        Some(client) => Either::A(future::ok(client)),
+        None => {
+          debug!("Opening a dedicated connection for {}", q);
+          Either::B(Either::A(connect(&connection).map(
+            move |(client, heartbeat)| {
+              *slot.lock().unwrap() = Some(DedicatedConnection {
+                client: client.clone(),
+                heartbeat,
+              });
+              client
+            },
+          )))
+        }
+      }
+    }
+    None => Either::B(Either::B(future::ok(client))),
+  };
+  consumer_client
+    .and_then(|client| client.create_channel().map_err(Error::from))
+    .and_then(move |channel| {
       debug!("Declaring queue {}", &q);
       channel
         .queue_declare(
@@ -81,10 +348,10 @@ fn create_reactor(
           },
           FieldTable::new(),
         )
-        .map(move |queue| (publisher_channel, channel, queue))
+        .map(move |queue| (channel, queue))
         .map_err(Error::from)
     })
-    .and_then(move |(publisher_channel, channel, queue)| {
+    .and_then(move |(channel, queue)| {
       debug!("Binding {}======{}=====>{}", &queue.name(), &rk, &xchg);
       channel
         .queue_bind(
@@ -94,92 +361,528 @@ fn create_reactor(
           QueueBindOptions::default(),
           FieldTable::new(),
         )
-        .map(move |_| (publisher_channel, channel, queue))
+        .map(move |_| (channel, queue))
         .map_err(Error::from)
     })
-    .and_then(move |(publisher_channel, channel, queue)| {
+    .and_then(move |(channel, queue)| {
       debug!("Consuming on {}", &queue.name());
       channel
         .basic_consume(
           &queue,
-          "", // consumer tag, should be empty if no reason to do otherwise
+          &consumer_tag,
           BasicConsumeOptions::default(),
           FieldTable::new(),
         )
-        .map(move |stream| (publisher_channel, channel, stream))
+        .map(move |stream| (channel, stream, queue.name()))
         .map_err(Error::from)
     })
-    .and_then(move |(publisher_channel, channel, stream)| {
+    .and_then(move |(channel, stream, queue_name)| {
       debug!("Stream of message is open, let's consume!");
-      let publisher = Arc::new(Mutex::new(publisher_channel));
-      stream.map_err(Error::from).for_each(move |delivery| {
+      let threshold = toxic.as_ref().and_then(ToxicSpec::messages);
+      let limit = threshold
+        .map(|n| n.saturating_sub(received.load(Ordering::SeqCst) as u64))
+        .unwrap_or_else(u64::max_value);
+      let deadline = match toxic.as_ref().map(ToxicSpec::deadline) {
+        Some(Ok(Some(deadline))) => {
+          Either::A(Delay::new(Instant::now() + deadline).map_err(Error::from))
+        }
+        _ => Either::B(future::empty()),
+      };
+      let trigger = toxic.as_ref().map(ToxicSpec::command);
+      let toxic_channel = channel.clone();
+      let count = received.clone();
+      let queue = queue_name.clone();
+      let stream = stream.map_err(Error::from).take(limit);
+      let consumed = stream.for_each(move |delivery| {
         let delivery_tag = delivery.delivery_tag;
         debug!("Received message {}", delivery_tag);
         let publisher = publisher.clone();
         let actions = action.clone();
-        let input_message = Message::from(delivery);
-        let (tx, rx) = futures::sync::mpsc::channel(0);
-        tokio::spawn(
-          futures::stream::iter_ok(actions)
-            .map(move |action| (action, input_message.clone()))
-            .for_each(move |(action, input_message)| {
-              let tx = tx.clone();
-              Delay::new(Instant::now() + Duration::from_secs(action.schedule.seconds as u64))
-                .then(move |_| {
-                  let action = action.clone();
-                  let input_message = input_message.clone();
-                  let mut rng = thread_rng();
-                  let evaluator = Random::new(&mut rng);
-                  debug!("Generating a message...");
-                  handle_message(&action, &input_message, &evaluator)
-                })
-                .and_then(move |msg| {
-                  debug!("We have a message: send it through channel");
-                  tx.send(msg).map(|_| ()).map_err(Error::from)
-                })
-                .map_err(|e| error!("Error: {}", e))
-            }),
-        );
-        let publisher = publisher.clone();
-        let channel = channel.clone();
-        tokio::spawn(
-          rx.map_err(|_| error!("Error on rx stream"))
-            .for_each(move |msg| {
-              debug!("Received a message to send from the channel");
-              let publisher = publisher.lock().unwrap();
-              publisher
-                .basic_publish(
-                  &msg.route.exchange,
-                  &msg.route.routing_key,
-                  msg.payload,
-                  BasicPublishOptions::default(),
-                  to_amqp_props(&msg.headers),
-                )
-                .map(|_| {
-                  info!("Message published!!");
-                  ()
-                })
-                .map_err(|e| error!("Publishing a message: {}", e))
+        let input_message = to_message(delivery, &queue_name);
+        if reads_json {
+          if let Err(e) = input_message.json_body.parse(&input_message.payload) {
+            let count = malformed.fetch_add(1, Ordering::SeqCst) + 1;
+            warn!(
+              "Malformed message {} on {} ({} so far): {}",
+              delivery_tag, queue_name, count, e
+            );
+          }
+        }
+        let index = received.fetch_add(1, Ordering::SeqCst) as u64;
+        let actions = match mode {
+          ActionMode::Choice(ref choice) => {
+            let chosen = choice.pick(index, actions.len(), &mut thread_rng());
+            debug!("Action {:?} chosen for message {}", chosen, delivery_tag);
+            chosen.map(|i| vec![actions[i].clone()]).unwrap_or_default()
+          }
+          _ => actions,
+        };
+        if mode == ActionMode::Chain {
+          tokio::spawn(run_chain(actions, input_message, publisher));
+          return channel.basic_ack(delivery_tag, false).map_err(Error::from);
+        }
+        for action in actions {
+          tokio::spawn(
+            schedule_action(
+              action,
+              input_message.clone(),
+              Variables::new(),
+              publisher.clone(),
+            )
+            .map(|_| ())
+            .map_err(|e| match e.downcast_ref::<ActionSkipped>() {
+              Some(skipped) => warn!("{}", skipped),
+              None => error!("Error: {}", e),
             }),
-        );
+          );
+        }
         channel.basic_ack(delivery_tag, false).map_err(Error::from)
-      })
+      });
+      let triggered = consumed
+        .select2(deadline)
+        .then(move |outcome| match outcome {
+          Ok(Either::A(_)) => {
+            Ok(threshold.map_or(false, |n| count.load(Ordering::SeqCst) as u64 >= n))
+          }
+          Ok(Either::B(_)) => Ok(true),
+          Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+        })
+        .map(move |triggered| if triggered { trigger } else { None });
+      let armed = toxic;
+      triggered
+        .select2(commands)
+        .then(move |outcome| match outcome {
+          Ok(Either::A((None, commands))) => Either::A(future::ok((None, armed, commands))),
+          Ok(Either::A((Some(command), commands))) => {
+            Either::A(future::ok((Some(command), None, commands)))
+          }
+          Ok(Either::B(((Some(command), commands), _))) => {
+            Either::A(future::ok((Some(command), armed, commands.into_future())))
+          }
+          Ok(Either::B(((None, commands), triggered))) => {
+            Either::B(triggered.map(move |command| {
+              let armed = if command.is_some() { None } else { armed };
+              (command, armed, commands.into_future())
+            }))
+          }
+          Err(Either::A((e, _))) => Either::A(future::err(e)),
+          Err(Either::B(_)) => Either::A(future::err(err_msg(
+            "The toxics requested for the reactor are lost",
+          ))),
+        })
+        .and_then(move |(command, armed, commands)| match command {
+          Some(command) => {
+            warn!("Toxic applied on {}: {:?}", queue, command.effect);
+            Either::A(
+              apply_toxic(
+                &command.effect,
+                toxic_channel,
+                &toxic_tag,
+                &dedicated_connection,
+              )
+              .map(move |cancelled| Stopped {
+                command: Some(command),
+                armed,
+                cancelled,
+                commands,
+              }),
+            )
+          }
+          None => Either::B(future::ok(Stopped {
+            command: None,
+            armed,
+            cancelled: None,
+            commands,
+          })),
+        })
     })
 }
 
-impl From<Delivery> for Message {
-  fn from(delivery: Delivery) -> Message {
-    Message {
-      payload: delivery.data,
-      route: Route {
-        exchange: delivery.exchange,
-        routing_key: delivery.routing_key,
-      },
-      headers: to_headers_map(&delivery.properties),
+/// Disconnects the reactor as the toxic says. Resolves to the channel whose consumer is cancelled, if any.
+fn apply_toxic(
+  effect: &ToxicEffect,
+  channel: Channel<AMQPStream>,
+  consumer_tag: &str,
+  dedicated: &Mutex<Option<DedicatedConnection>>,
+) -> impl Future<Item = Option<Channel<AMQPStream>>, Error = Error> {
+  match effect {
+    ToxicEffect::CloseChannel => Either::A(
+      channel
+        .close(200, "Closed by a toxic")
+        .map(|_| None)
+        .map_err(Error::from),
+    ),
+    ToxicEffect::CancelConsumer => Either::B(Either::A(
+      cancel_consumer(&channel, consumer_tag).map(move |_| Some(channel)),
+    )),
+    ToxicEffect::DropConnection => {
+      // The consumer is already dropped: once the heartbeats stop, dropping the client and the channel releases the
+      // last handles on the dedicated connection, which closes its socket.
+      if let Some(DedicatedConnection { client, heartbeat }) = dedicated.lock().unwrap().take() {
+        heartbeat.stop();
+        drop(client);
+      }
+      drop(channel);
+      Either::B(Either::B(future::ok(None)))
     }
   }
 }
 
+/// Cancels the consumer, and resolves once the broker confirms it. lapin-futures has no `basic_cancel`: the request is
+/// sent through the connection state shared by the channels, and the answer awaited the way lapin-futures awaits its own
+/// requests.
+fn cancel_consumer(
+  channel: &Channel<AMQPStream>,
+  consumer_tag: &str,
+) -> impl Future<Item = (), Error = Error> {
+  let transport = channel.transport.clone();
+  let requested = channel
+    .transport
+    .lock()
+    .conn
+    .basic_cancel(channel.id, consumer_tag.to_owned(), false)
+    .map_err(|e| format_err!("Cannot cancel the consumer: {}", e));
+  requested.into_future().and_then(move |request_id| {
+    future::poll_fn(move || {
+      let mut transport = transport.lock();
+      transport.poll().map_err(Error::from)?;
+      match transport.conn.is_finished(request_id) {
+        Some(true) => Ok(Async::Ready(())),
+        _ => {
+          task::current().notify();
+          Ok(Async::NotReady)
+        }
+      }
+    })
+  })
+}
+
+/// The number of messages of a load waiting for their due time, or being published.
+const MAX_IN_FLIGHT: usize = 1000;
+
+/// Publishes the messages of the actions, in turn, at the rate of the load, then logs a report. The load has a
+/// connection of its own, and its channels are in confirm mode: the latency of a message is the time the broker takes
+/// to confirm it.
+fn run_load(
+  connection: &str,
+  actions: Vec<ActionSpec>,
+  load: LoadSpec,
+) -> impl Future<Item = (), Error = ()> {
+  let connection = connection.to_owned();
+  let report = Arc::new(Mutex::new(LoadReport::default()));
+  let summary = report.clone();
+  let channel_count = load.channels;
+  load
+    .total()
+    .into_future()
+    .and_then(move |total| connect(&connection).map(move |connected| (total, connected)))
+    .and_then(move |(total, (client, heartbeat))| {
+      info!("Starting a load of {} messages", total);
+      let channels = (0..channel_count).map(move |_| {
+        client
+          .create_confirm_channel(ConfirmSelectOptions::default())
+          .map_err(Error::from)
+      });
+      future::join_all(channels).map(move |channels| (total, channels, heartbeat))
+    })
+    .and_then(move |(total, channels, heartbeat)| {
+      let start = Instant::now();
+      let input_message = no_input_message();
+      futures::stream::iter_ok(0..total)
+        .map(move |index| {
+          let action = actions[(index % actions.len() as u64) as usize].clone();
+          let channel = channels[(index % channels.len() as u64) as usize].clone();
+          let input_message = input_message.clone();
+          // A message whose due time cannot be computed stops the load, rather than being published at once.
+          load.due(index).into_future().and_then(move |due| {
+            Delay::new(start + due).then(move |_| {
+              let built = {
+                let mut rng = thread_rng();
+                let evaluator = Random::new(&mut rng);
+                handle_message(&action, &input_message, &evaluator)
+              };
+              match built {
+                Ok(msg) => {
+                  let sent_at = Instant::now();
+                  Either::A(publish_confirmed(&channel, msg).then(move |published| {
+                    if let Err(ref e) = published {
+                      warn!("Cannot publish a message of the load: {}", e);
+                    }
+                    Ok::<_, Error>((published.is_ok(), sent_at.elapsed()))
+                  }))
+                }
+                Err(e) => {
+                  warn!("Cannot build a message of the load: {}", e);
+                  Either::B(future::ok((false, Duration::default())))
+                }
+              }
+            })
+          })
+        })
+        .buffer_unordered(MAX_IN_FLIGHT)
+        .for_each(move |(published, latency)| {
+          report.lock().unwrap().record(published, latency);
+          Ok(())
+        })
+        .then(move |done| {
+          // The channels are dropped: once the heartbeats stop, the connection of the load is released.
+          heartbeat.stop();
+          done.map(move |_| start)
+        })
+    })
+    .map(move |start| {
+      let mut report = summary.lock().unwrap();
+      report.elapsed = start.elapsed();
+      info!("Load done: {}", *report);
+    })
+    .map_err(|e| error!("Load error: {}", e))
+}
+
+/// Publishes the message on a confirm channel. Resolves once the broker confirms it.
+fn publish_confirmed(
+  channel: &Channel<AMQPStream>,
+  msg: Message,
+) -> impl Future<Item = (), Error = Error> {
+  channel
+    .basic_publish(
+      &msg.route.exchange,
+      &msg.route.routing_key,
+      msg.payload,
+      BasicPublishOptions::default(),
+      to_amqp_props(&msg.headers),
+    )
+    .map_err(Error::from)
+    .and_then(|confirmed| match confirmed {
+      Some(_) => Ok(()),
+      None => Err(err_msg("The broker did not confirm the message")),
+    })
+}
+
+/// What the actions of generators, which do not react to a message, are given as an incoming message.
+fn no_input_message() -> Message {
+  Message {
+    payload: vec![],
+    headers: Headers::new(),
+    route: Route {
+      exchange: String::new(),
+      routing_key: String::new(),
+    },
+    delivery: None,
+    json_body: JsonBody::default(),
+  }
+}
+
+/// Runs the actions one after the other. Each delay starts once the previous message is published, and each action
+/// sees the variables of the previous ones. The first failure stops the chain.
+fn run_chain(
+  actions: Vec<ActionSpec>,
+  input_message: Message,
+  publisher: Publisher,
+) -> impl Future<Item = (), Error = ()> {
+  futures::stream::iter_ok(actions)
+    .fold(Variables::new(), move |previous, action| {
+      schedule_action(action, input_message.clone(), previous, publisher.clone())
+    })
+    .map(|_| debug!("End of the chain"))
+    .map_err(|e: Error| match e.downcast_ref::<ActionSkipped>() {
+      Some(skipped) => warn!("Chain stopped: {}", skipped),
+      None => error!("Chain stopped: {}", e),
+    })
+}
+
+/// Computes the delay of the action on reception of the message, then runs the action once the delay is elapsed. A
+/// failure to compute the delay is handled according to the `on_error` policy of the action: the error message, if
+/// any, is published at once.
+fn schedule_action(
+  action: ActionSpec,
+  input_message: Message,
+  previous: Variables,
+  publisher: Publisher,
+) -> impl Future<Item = Variables, Error = Error> {
+  let scheduled = {
+    let vars = match action.repeat {
+      Some(_) => iteration_variables(&previous, 0),
+      None => previous.clone(),
+    };
+    let mut rng = thread_rng();
+    let evaluator = Random::new(&mut rng);
+    schedule_delay(
+      &action,
+      &input_message,
+      &vars,
+      &evaluator,
+      &mut thread_rng(),
+    )
+  };
+  match scheduled {
+    Ok((delay, scheduled)) => Either::A(
+      Delay::new(Instant::now() + delay)
+        .map_err(Error::from)
+        .and_then(move |_| run_action(action, input_message, previous, scheduled, publisher)),
+    ),
+    Err(e) => {
+      let faults = action.faults.clone();
+      Either::B(
+        handle_failure(&action, &input_message, &previous, e)
+          .into_future()
+          .and_then(move |(msg, variables)| {
+            send(&publisher, faults.as_ref(), msg).map(|_| variables)
+          }),
+      )
+    }
+  }
+}
+
+/// Publishes the message of the action, or its messages when it is repeated. The `scheduled` variables, computed along
+/// with the delay, are kept by the first message. Once the messages are published, the toxic of the action, if any, is
+/// requested. Resolves to the variables of the last message.
+fn run_action(
+  action: ActionSpec,
+  input_message: Message,
+  previous: Variables,
+  scheduled: Variables,
+  publisher: Publisher,
+) -> impl Future<Item = Variables, Error = Error> {
+  let mut scheduled = Some(scheduled);
+  let toxic = action.toxic.clone();
+  let requester = publisher.clone();
+  future::loop_fn(0, move |iteration| {
+    let repeat = action.repeat.clone();
+    let generated = {
+      let vars = match repeat {
+        Some(_) => iteration_variables(&previous, iteration),
+        None => previous.clone(),
+      };
+      let mut rng = thread_rng();
+      let evaluator = Random::new(&mut rng);
+      debug!("Generating a message...");
+      let scheduled = scheduled.take().unwrap_or_default();
+      handle_scheduled_message(&action, &input_message, &vars, &scheduled, &evaluator)
+    };
+    let publisher = publisher.clone();
+    let faults = action.faults.clone();
+    generated
+      .into_future()
+      .and_then(move |(msg, variables)| send(&publisher, faults.as_ref(), msg).map(|_| variables))
+      .and_then(move |variables| {
+        let next = match repeat {
+          Some(ref repeat) => repeat.is_over(iteration, &variables).and_then(|over| {
+            if over {
+              Ok(None)
+            } else {
+              repeat.interval().map(Some)
+            }
+          }),
+          None => Ok(None),
+        };
+        next.map(|next| (variables, next))
+      })
+      .and_then(move |(variables, next)| match next {
+        Some(interval) => Either::A(
+          Delay::new(Instant::now() + interval)
+            .map_err(Error::from)
+            .map(move |_| Loop::Continue(iteration + 1)),
+        ),
+        None => Either::B(future::ok(Loop::Break(variables))),
+      })
+  })
+  .map(move |variables| {
+    if let Some(toxic) = toxic {
+      request_toxic(&requester.toxics, &requester.queue, toxic);
+    }
+    variables
+  })
+}
+
+/// Where the actions of a reactor publish their messages.
+#[derive(Clone)]
+struct Publisher {
+  channel: Arc<Mutex<Channel<AMQPStream>>>,
+  queue: String,
+  faults: Option<FaultsSpec>,
+  counters: Arc<FaultCounters>,
+  /// Hands the toxics of the actions to the reactor.
+  toxics: UnboundedSender<ToxicCommand>,
+}
+
+/// Publishes the message, once the faults of the action, or else those of the reactor, are injected.
+fn send(
+  publisher: &Publisher,
+  faults: Option<&FaultsSpec>,
+  msg: Message,
+) -> impl Future<Item = (), Error = Error> {
+  let faults = match faults.or_else(|| publisher.faults.as_ref()) {
+    Some(faults) => faults,
+    None => return Either::A(publish(&publisher.channel, msg)),
+  };
+  let (publications, injected) = match faults.inject(msg, &mut thread_rng()) {
+    Ok(result) => result,
+    Err(e) => return Either::B(Either::A(future::err(e))),
+  };
+  for fault in injected.iter() {
+    let count = publisher.counters.record(fault);
+    warn!(
+      "Fault injected on {}: {} ({} {} faults so far)",
+      publisher.queue,
+      fault,
+      count,
+      fault.kind()
+    );
+  }
+  let mut pending = vec![];
+  for publication in publications {
+    let Publication {
+      message,
+      delay,
+      background,
+    } = publication;
+    let channel = publisher.channel.clone();
+    let published = Delay::new(Instant::now() + delay)
+      .map_err(Error::from)
+      .and_then(move |_| publish(&channel, message));
+    if background {
+      tokio::spawn(published.map_err(|e| error!("Publishing a message: {}", e)));
+    } else {
+      pending.push(published);
+    }
+  }
+  Either::B(Either::B(future::join_all(pending).map(|_| ())))
+}
+
+fn publish(
+  publisher: &Arc<Mutex<Channel<AMQPStream>>>,
+  msg: Message,
+) -> impl Future<Item = (), Error = Error> {
+  let publisher = publisher.lock().unwrap();
+  publisher
+    .basic_publish(
+      &msg.route.exchange,
+      &msg.route.routing_key,
+      msg.payload,
+      BasicPublishOptions::default(),
+      to_amqp_props(&msg.headers),
+    )
+    .map(|_| info!("Message published!!"))
+    .map_err(Error::from)
+}
+
+fn to_message(delivery: Delivery, queue_name: &str) -> Message {
+  Message {
+    headers: to_headers_map(&delivery.properties),
+    delivery: Some(DeliveryInfo {
+      delivery_tag: delivery.delivery_tag,
+      redelivered: delivery.redelivered,
+      queue: queue_name.to_owned(),
+    }),
+    payload: delivery.data,
+    route: Route {
+      exchange: delivery.exchange,
+      routing_key: delivery.routing_key,
+    },
+    json_body: JsonBody::default(),
+  }
+}
+
 fn to_amqp_props(headers: &Headers) -> BasicProperties {
   let (properties, custom_headers) = headers.iter().fold(
     (BasicProperties::default(), FieldTable::new()),
@@ -193,20 +896,14 @@ fn to_amqp_props(headers: &Headers) -> BasicProperties {
       ("expiration", Str(s)) => (props.with_expiration(s.clone()), h),
       ("message_id", Str(s)) => (props.with_message_id(s.clone()), h),
       ("timestamp", Int(i)) => (props.with_timestamp(*i as u64), h),
+      ("timestamp", Typed(TypedLit::Timestamp(t))) => (props.with_timestamp(*t), h),
       ("type", Str(s)) => (props.with_type_(s.clone()), h),
       ("user_id", Str(s)) => (props.with_user_id(s.clone()), h),
       ("app_id", Str(s)) => (props.with_app_id(s.clone()), h),
       ("cluster_id", Str(s)) => (props.with_cluster_id(s.clone()), h),
-      (_, Str(s)) => {
-        h.insert(k.clone(), AMQPValue::LongString(s.clone()));
-        (props, h)
-      }
-      (_, Int(i)) => {
-        h.insert(k.clone(), AMQPValue::LongLongInt(*i));
-        (props, h)
-      }
-      (_, Real(r)) => {
-        h.insert(k.clone(), AMQPValue::Double(*r));
+      (_, Null) => (props, h),
+      (_, v) => {
+        h.insert(k.clone(), to_amqp_value(v));
         (props, h)
       }
     },
@@ -214,25 +911,77 @@ fn to_amqp_props(headers: &Headers) -> BasicProperties {
   properties.with_headers(custom_headers)
 }
 
-fn to_hvalue(amqp_value: &AMQPValue) -> Option<HValue> {
+fn json_to_amqp_value(json: &JsonValue) -> AMQPValue {
+  match json {
+    JsonValue::Null => AMQPValue::Void,
+    JsonValue::Bool(b) => AMQPValue::Boolean(*b),
+    JsonValue::Number(n) => match n.as_i64() {
+      Some(i) => AMQPValue::LongLongInt(i),
+      None => AMQPValue::Double(n.as_f64().unwrap_or_default()),
+    },
+    JsonValue::String(s) => AMQPValue::LongString(s.clone()),
+    JsonValue::Array(a) => AMQPValue::FieldArray(a.iter().map(json_to_amqp_value).collect()),
+    JsonValue::Object(o) => AMQPValue::FieldTable(
+      o.iter()
+        .map(|(k, v)| (k.clone(), json_to_amqp_value(v)))
+        .collect(),
+    ),
+  }
+}
+
+fn to_amqp_value(value: &HValue) -> AMQPValue {
+  match value {
+    Int(i) => AMQPValue::LongLongInt(*i),
+    Str(s) => AMQPValue::LongString(s.clone()),
+    Real(r) => AMQPValue::Double(*r),
+    Bool(b) => AMQPValue::Boolean(*b),
+    Null => AMQPValue::Void,
+    Typed(t) => match t {
+      TypedLit::ShortShortInt(i) => AMQPValue::ShortShortInt(*i),
+      TypedLit::ShortShortUInt(i) => AMQPValue::ShortShortUInt(*i),
+      TypedLit::ShortInt(i) => AMQPValue::ShortInt(*i),
+      TypedLit::ShortUInt(i) => AMQPValue::ShortUInt(*i),
+      TypedLit::LongInt(i) => AMQPValue::LongInt(*i),
+      TypedLit::LongUInt(i) => AMQPValue::LongUInt(*i),
+      TypedLit::Float(f) => AMQPValue::Float(*f),
+      TypedLit::Decimal { scale, value } => AMQPValue::DecimalValue(DecimalValue {
+        scale: *scale,
+        value: *value,
+      }),
+      TypedLit::Timestamp(t) => AMQPValue::Timestamp(*t),
+      TypedLit::Bytes(b) => AMQPValue::ByteArray(b.clone()),
+      TypedLit::Table(t) => AMQPValue::FieldTable(
+        t.iter()
+          .map(|(k, v)| (k.clone(), to_amqp_value(v)))
+          .collect(),
+      ),
+      TypedLit::Array(a) => AMQPValue::FieldArray(a.iter().map(to_amqp_value).collect()),
+    },
+    Json(j) => json_to_amqp_value(j),
+  }
+}
+
+fn to_hvalue(amqp_value: &AMQPValue) -> HValue {
   match amqp_value {
-    AMQPValue::ShortShortInt(i) => Some(Int(*i as i64)),
-    AMQPValue::ShortShortUInt(i) => Some(Int(*i as i64)),
-    AMQPValue::ShortInt(i) => Some(Int(*i as i64)),
-    AMQPValue::ShortUInt(i) => Some(Int(*i as i64)),
-    AMQPValue::LongInt(i) => Some(Int(*i as i64)),
-    AMQPValue::LongUInt(i) => Some(Int(*i as i64)),
-    AMQPValue::LongLongInt(i) => Some(Int(*i as i64)),
-    AMQPValue::LongString(s) => Some(Str(s.clone())),
-    AMQPValue::Timestamp(i) => Some(Int(*i as i64)),
-    AMQPValue::Float(_) => None,
-    AMQPValue::Double(_) => None,
-    AMQPValue::DecimalValue(_) => None,
-    AMQPValue::FieldArray(_) => None,
-    AMQPValue::FieldTable(_) => None,
-    AMQPValue::ByteArray(_) => None,
-    AMQPValue::Void => None,
-    AMQPValue::Boolean(_) => None,
+    AMQPValue::ShortShortInt(i) => Typed(TypedLit::ShortShortInt(*i)),
+    AMQPValue::ShortShortUInt(i) => Typed(TypedLit::ShortShortUInt(*i)),
+    AMQPValue::ShortInt(i) => Typed(TypedLit::ShortInt(*i)),
+    AMQPValue::ShortUInt(i) => Typed(TypedLit::ShortUInt(*i)),
+    AMQPValue::LongInt(i) => Typed(TypedLit::LongInt(*i)),
+    AMQPValue::LongUInt(i) => Typed(TypedLit::LongUInt(*i)),
+    AMQPValue::LongLongInt(i) => Int(*i),
+    AMQPValue::LongString(s) => Str(s.clone()),
+    AMQPValue::Timestamp(t) => Typed(TypedLit::Timestamp(*t)),
+    AMQPValue::Float(f) => Real(f64::from(*f)),
+    AMQPValue::Double(d) => Real(*d),
+    AMQPValue::DecimalValue(d) => Real(f64::from(d.value) / 10f64.powi(i32::from(d.scale))),
+    AMQPValue::FieldArray(a) => Typed(TypedLit::Array(a.iter().map(to_hvalue).collect())),
+    AMQPValue::FieldTable(t) => Typed(TypedLit::Table(
+      t.iter().map(|(k, v)| (k.clone(), to_hvalue(v))).collect(),
+    )),
+    AMQPValue::ByteArray(b) => Typed(TypedLit::Bytes(b.clone())),
+    AMQPValue::Void => Null,
+    AMQPValue::Boolean(b) => Bool(*b),
   }
 }
 
@@ -275,13 +1024,7 @@ fn to_headers_map(props: &BasicProperties) -> Headers {
   match props.headers() {
     Some(t) => {
       for (k, v) in t.iter() {
-        match to_hvalue(v) {
-          Some(hv) => {
-            headers.insert(k.clone(), hv);
-            ()
-          }
-          None => (),
-        }
+        headers.insert(k.clone(), to_hvalue(v));
       }
       ()
     }
@@ -370,13 +1113,56 @@ fn to_headers_map(props: &BasicProperties) -> Headers {
 fn create_client(
   amqp_connection: &str,
 ) -> impl Future<Item = lapin::client::Client<AMQPStream>, Error = Error> {
+  connect(amqp_connection).map(|(client, _)| client)
+}
+
+fn connect(
+  amqp_connection: &str,
+) -> impl Future<
+  Item = (
+    lapin::client::Client<AMQPStream>,
+    lapin::client::HeartbeatHandle,
+  ),
+  Error = Error,
+> {
   trace!("Opening a connection");
   amqp_connection
     .connect_cancellable(|err| {
       error!("heartbeat error: {:?}", err);
     })
     .map_err(Error::from)
-    .map(|(client, _)| client)
+}
+
+/// Publishes `count` messages built by the action, one after the other, with the `iteration` variable holding the index
+/// of the message. The schedule of the action is ignored.
+pub fn publish_messages(connection: &str, mut action: ActionSpec, count: u32) -> Result<(), Error> {
+  action.validate()?;
+  action.compile()?;
+  let mut runtime = tokio::runtime::Runtime::new()?;
+  let program = create_client(connection)
+    .and_then(|client| client.create_channel().map_err(Error::from))
+    .and_then(move |channel| {
+      let channel = Arc::new(Mutex::new(channel));
+      let closing = channel.clone();
+      futures::stream::iter_ok(0..count)
+        .for_each(move |iteration| {
+          let generated = {
+            let mut rng = thread_rng();
+            let evaluator = Random::new(&mut rng);
+            let variables = iteration_variables(&Variables::new(), iteration);
+            handle_chained_message(&action, &no_input_message(), &variables, &evaluator)
+          };
+          let channel = channel.clone();
+          generated
+            .into_future()
+            .and_then(move |(msg, _)| publish(&channel, msg))
+        })
+        .and_then(move |_| {
+          let channel = closing.lock().unwrap();
+          channel.close(200, "Bye").map_err(Error::from)
+        })
+    });
+  runtime.block_on(program)
 }
 
 pub fn run(config_file_path: &str) {